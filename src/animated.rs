@@ -0,0 +1,286 @@
+use crate::backend::Colouring;
+use crate::renderer::PixelBuffer;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+// The kinds of file a whole animation can be saved as
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnimatedFormat {
+    Gif,
+    Apng,
+}
+
+impl AnimatedFormat {
+    // the format a file name's extension is for, with .png meaning an animated png
+    pub fn from_path(path: &Path) -> Option<AnimatedFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gif" => Some(AnimatedFormat::Gif),
+            "png" | "apng" => Some(AnimatedFormat::Apng),
+            _ => None,
+        }
+    }
+}
+
+// How an animation plays back
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Playback {
+    // how long each frame stays up, in milliseconds. GIFs only go to the nearest 10
+    pub delay: u32,
+    // how many times it plays, 0 for forever
+    pub loops: u32,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Playback {
+            delay: 40,
+            loops: 0,
+        }
+    }
+}
+
+// Writes the frames of an animation into a file one at a time, so only the frame being written
+// needs to be in memory
+pub enum AnimatedWriter {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        palette: GifPalette,
+        delay: u16,
+    },
+    Apng(png::Writer<BufWriter<File>>),
+}
+
+impl AnimatedWriter {
+    // Start a file for an animation of frames frames. A GIF gets one palette for all of them,
+    // made from the colours they're rendered with, see GifPalette
+    pub fn create(
+        path: &Path,
+        format: AnimatedFormat,
+        (width, height): (u64, u64),
+        frames: u64,
+        playback: Playback,
+        colours: &[(u8, u8, u8)],
+        colouring: Colouring,
+    ) -> Result<AnimatedWriter, String> {
+        let file = File::create(path)
+            .map_err(|error| format!("couldn't write {}: {}", path.display(), error))?;
+        let file = BufWriter::new(file);
+        match format {
+            AnimatedFormat::Gif => {
+                let gif_error = |error: gif::EncodingError| {
+                    format!("couldn't save {}: {}", path.display(), error)
+                };
+                let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+                    return Err("GIFs can't be more than 65535 pixels across".to_string());
+                };
+                let palette = GifPalette::new(colours, colouring);
+                let flat: Vec<u8> = palette
+                    .colours
+                    .iter()
+                    .flat_map(|&(r, g, b)| [r, g, b])
+                    .collect();
+                let mut encoder =
+                    gif::Encoder::new(file, width, height, &flat).map_err(gif_error)?;
+                // a GIF counts how many more times to play after the first, and plays once without one
+                let repeat = match playback.loops {
+                    0 => gif::Repeat::Infinite,
+                    loops => gif::Repeat::Finite((loops - 1).min(u16::MAX as u32) as u16),
+                };
+                encoder.set_repeat(repeat).map_err(gif_error)?;
+                Ok(AnimatedWriter::Gif {
+                    encoder,
+                    palette,
+                    delay: ((playback.delay + 5) / 10).min(u16::MAX as u32) as u16,
+                })
+            }
+            AnimatedFormat::Apng => {
+                let png_error = |error: png::EncodingError| {
+                    format!("couldn't save {}: {}", path.display(), error)
+                };
+                let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder
+                    .set_animated(frames as u32, playback.loops)
+                    .map_err(png_error)?;
+                encoder
+                    .set_frame_delay(playback.delay.min(u16::MAX as u32) as u16, 1000)
+                    .map_err(png_error)?;
+                Ok(AnimatedWriter::Apng(
+                    encoder.write_header().map_err(png_error)?,
+                ))
+            }
+        }
+    }
+
+    pub fn write_frame(&mut self, frame: &PixelBuffer) -> Result<(), String> {
+        match self {
+            AnimatedWriter::Gif {
+                encoder,
+                palette,
+                delay,
+            } => {
+                let indices = frame
+                    .pixels()
+                    .par_chunks_exact(3)
+                    .map(|pixel| palette.index((pixel[0], pixel[1], pixel[2])))
+                    .collect();
+                let frame = gif::Frame {
+                    width: frame.width() as u16,
+                    height: frame.height() as u16,
+                    buffer: Cow::Owned(indices),
+                    delay: *delay,
+                    ..Default::default()
+                };
+                encoder
+                    .write_frame(&frame)
+                    .map_err(|error| format!("couldn't save the GIF: {}", error))
+            }
+            AnimatedWriter::Apng(writer) => writer
+                .write_image_data(frame.pixels())
+                .map_err(|error| format!("couldn't save the PNG: {}", error)),
+        }
+    }
+
+    // everything after the last frame, which for an APNG checks they were all there
+    pub fn finish(self) -> Result<(), String> {
+        match self {
+            AnimatedWriter::Gif { encoder, .. } => encoder
+                .into_inner()
+                .map(drop)
+                .map_err(|error| format!("couldn't save the GIF: {}", error)),
+            AnimatedWriter::Apng(writer) => writer
+                .finish()
+                .map_err(|error| format!("couldn't save the PNG: {}", error)),
+        }
+    }
+}
+
+// The 256 colours of a GIF, picked to suit the palette the frames were coloured with rather than
+// worked out from the frames, so it's the same for every frame however the colours cycle. Escape
+// time colours all come straight from the palette, so a palette with up to 255 different colours
+// gets all of them along with black for the inside of the set, and every pixel is an exact match.
+// Bigger palettes, like the default one's 713 colours, get 255 of them spread evenly along it,
+// and the rest are drawn as whichever of those is closest. Distance colouring is shades of grey
+// instead, which get all 256 greys
+pub struct GifPalette {
+    colours: Vec<(u8, u8, u8)>,
+    // every colour of the palette, with where it or the closest colour to it is
+    known: HashMap<(u8, u8, u8), u8>,
+    // the closest colour to everything else, by the top 5 bits of red, green and blue
+    nearest: Vec<u8>,
+}
+
+impl GifPalette {
+    pub fn new(palette: &[(u8, u8, u8)], colouring: Colouring) -> GifPalette {
+        let colours: Vec<(u8, u8, u8)> = match colouring {
+            Colouring::Distance => (0..=255).map(|grey| (grey, grey, grey)).collect(),
+            _ => {
+                // each colour once, in the order they come along the palette
+                let mut seen = HashSet::new();
+                let distinct: Vec<(u8, u8, u8)> = palette
+                    .iter()
+                    .copied()
+                    .filter(|&colour| seen.insert(colour))
+                    .collect();
+                let samples = distinct.len().min(255);
+                std::iter::once((0, 0, 0))
+                    .chain((0..samples).map(|i| distinct[i * distinct.len() / samples]))
+                    .collect()
+            }
+        };
+        let mut known = HashMap::new();
+        for (index, &colour) in colours.iter().enumerate() {
+            known.entry(colour).or_insert(index as u8);
+        }
+        if colouring != Colouring::Distance {
+            for &colour in palette {
+                known
+                    .entry(colour)
+                    .or_insert_with(|| closest(&colours, colour));
+            }
+        }
+        let nearest = (0..1 << 15)
+            .into_par_iter()
+            .map(|cell: u32| {
+                let channel = |shift: u32| (cell >> shift & 31) << 3 | 4;
+                closest(
+                    &colours,
+                    (channel(10) as u8, channel(5) as u8, channel(0) as u8),
+                )
+            })
+            .collect();
+        GifPalette {
+            colours,
+            known,
+            nearest,
+        }
+    }
+
+    pub fn index(&self, colour: (u8, u8, u8)) -> u8 {
+        match self.known.get(&colour) {
+            Some(&index) => index,
+            None => {
+                let (r, g, b) = colour;
+                let cell = (r as usize >> 3) << 10 | (g as usize >> 3) << 5 | b as usize >> 3;
+                self.nearest[cell]
+            }
+        }
+    }
+}
+
+// where the closest of some colours to another one is
+fn closest(colours: &[(u8, u8, u8)], (r, g, b): (u8, u8, u8)) -> u8 {
+    let distance = |&(_, &(cr, cg, cb)): &(usize, &(u8, u8, u8))| {
+        (r as i32 - cr as i32).pow(2)
+            + (g as i32 - cg as i32).pow(2)
+            + (b as i32 - cb as i32).pow(2)
+    };
+    colours
+        .iter()
+        .enumerate()
+        .min_by_key(distance)
+        .map_or(0, |(index, _)| index as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colours::{DEFAULT_COLOURS, GREEN_AND_BLACK, RAINBOW_COLOURS};
+
+    // how far each palette colour ends up from the colour it's drawn as in a GIF, at the most
+    fn worst_error(palette: &[(u8, u8, u8)]) -> i32 {
+        let gif = GifPalette::new(palette, Colouring::EscapeTime);
+        palette
+            .iter()
+            .map(|&(r, g, b)| {
+                let (gr, gg, gb) = gif.colours[gif.index((r, g, b)) as usize];
+                (r as i32 - gr as i32)
+                    .abs()
+                    .max((g as i32 - gg as i32).abs())
+                    .max((b as i32 - gb as i32).abs())
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn palettes_fit_in_gifs() {
+        // these have fewer than 256 colours, so every one of them gets its own
+        assert_eq!(worst_error(&RAINBOW_COLOURS), 0);
+        assert_eq!(worst_error(&GREEN_AND_BLACK), 0);
+        // the default has 713, which all go to the closest of the 255 that fit
+        assert!(worst_error(&DEFAULT_COLOURS) <= 3);
+        let gif = GifPalette::new(&DEFAULT_COLOURS, Colouring::EscapeTime);
+        for &colour in &DEFAULT_COLOURS {
+            assert_eq!(gif.index(colour), closest(&gif.colours, colour));
+        }
+        assert_eq!(gif.colours.len(), 256);
+        assert_eq!(gif.index((0, 0, 0)), 0);
+    }
+}
@@ -0,0 +1,475 @@
+use crate::animated::{AnimatedFormat, AnimatedWriter, Playback};
+use crate::backend::Formula;
+use crate::export::{export_image, render_image};
+use crate::precision::{ComplexDecimal, Decimal};
+use crate::renderer::RenderParams;
+use crate::CANVAS_SIZE;
+use image::ImageFormat;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+
+// A view the animation passes through exactly, at one of its frames
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe {
+    pub frame: u64,
+    pub centre: ComplexDecimal,
+    // the zoom the explorer shows, which fits the same part of the plane across
+    // any size of image as the explorer does across its canvas
+    pub zoom: f64,
+    pub max_iterations: u64,
+    pub palette_offset: f64,
+}
+
+impl Keyframe {
+    // the view on top of everything else about how to render it, which comes from base
+    pub fn params<F: Formula + Clone>(&self, base: &RenderParams<F>) -> RenderParams<F> {
+        let resolution = CANVAS_SIZE as f64 / (self.zoom * base.width.max(1) as f64);
+        RenderParams {
+            centre: self.centre.clone(),
+            resolution: Decimal::from_f64(resolution, digits_for(resolution)).normalised(),
+            max_iterations: self.max_iterations,
+            palette_offset: self.palette_offset,
+            ..base.clone()
+        }
+    }
+}
+
+// enough digits after the decimal point to tell apart points a pixel of this size apart, plus some spare
+fn digits_for(pixel_size: f64) -> u32 {
+    (-pixel_size.log10()).ceil().max(0.0) as u32 + 20
+}
+
+// A zoom video as the keyframes it goes through, in order of frame. In between them the zoom
+// changes geometrically, so it zooms at the same rate all the way, and the centre follows a
+// Catmull-Rom spline through the centres of the keyframes. How fast the centre moves along
+// each part of the spline goes by the zoom too, so it moves steadily across the screen rather
+// than arriving at a deep keyframe in the last frame or two
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Animation {
+    keyframes: Vec<Keyframe>,
+}
+
+impl Animation {
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Animation {
+        keyframes.sort_by_key(|keyframe| keyframe.frame);
+        keyframes.dedup_by_key(|keyframe| keyframe.frame);
+        Animation { keyframes }
+    }
+
+    // A loop that goes once round the palette at one view, stopping a frame short of where it
+    // started so that it carries on smoothly when it plays again
+    pub fn palette_cycle(view: Keyframe, frames: u64) -> Animation {
+        let last = frames.saturating_sub(1);
+        Animation::new(vec![
+            Keyframe {
+                frame: last,
+                palette_offset: view.palette_offset + last as f64 / frames.max(1) as f64,
+                ..view.clone()
+            },
+            Keyframe { frame: 0, ..view },
+        ])
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    // add a keyframe, replacing whichever one was on the same frame
+    pub fn insert(&mut self, keyframe: Keyframe) {
+        match self
+            .keyframes
+            .binary_search_by_key(&keyframe.frame, |existing| existing.frame)
+        {
+            Ok(index) => self.keyframes[index] = keyframe,
+            Err(index) => self.keyframes.insert(index, keyframe),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keyframes.clear()
+    }
+
+    // how many frames there are, from frame 0 to the last keyframe
+    pub fn frames(&self) -> u64 {
+        self.keyframes.last().map_or(0, |last| last.frame + 1)
+    }
+
+    // The view at any frame, which stays on the first or last keyframe before or after them
+    pub fn view_at(&self, frame: u64) -> Option<Keyframe> {
+        let first = self.keyframes.first()?;
+        let last = self.keyframes.last()?;
+        if frame <= first.frame {
+            return Some(Keyframe {
+                frame,
+                ..first.clone()
+            });
+        }
+        if frame >= last.frame {
+            return Some(Keyframe {
+                frame,
+                ..last.clone()
+            });
+        }
+        // the keyframes either side, and the ones either side of those for the shape of the spline
+        let next = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.frame <= frame);
+        let at = |index: usize| &self.keyframes[index.min(self.keyframes.len() - 1)];
+        let (before, start, end, after) = (
+            at(next.saturating_sub(2)),
+            at(next - 1),
+            at(next),
+            at(next + 1),
+        );
+
+        let t = (frame - start.frame) as f64 / (end.frame - start.frame) as f64;
+        let zoom_change = (end.zoom / start.zoom).ln();
+        let zoom = start.zoom * (t * zoom_change).exp();
+        // The spline gets worked out from whichever end is zoomed in further, as that's where
+        // it needs the most digits, with how far along it is from there. That goes by how far
+        // the screen has moved, which is the integral of 1 / zoom over t
+        let (along, points) = if zoom_change >= 0.0 {
+            let from_end = if zoom_change < 1e-9 {
+                1.0 - t
+            } else {
+                (-t * zoom_change).exp() * (-(1.0 - t) * zoom_change).exp_m1()
+                    / (-zoom_change).exp_m1()
+            };
+            (from_end, [after, end, start, before])
+        } else {
+            let from_start = (-t * zoom_change).exp_m1() / (-zoom_change).exp_m1();
+            (from_start, [before, start, end, after])
+        };
+        let pixel_size = CANVAS_SIZE as f64 / zoom;
+        let centre = spline(
+            points.map(|keyframe| &keyframe.centre),
+            along,
+            digits_for(pixel_size),
+        );
+
+        let lerp = |from: f64, to: f64| from + (to - from) * t;
+        Some(Keyframe {
+            frame,
+            centre,
+            zoom,
+            max_iterations: lerp(start.max_iterations as f64, end.max_iterations as f64).round()
+                as u64,
+            palette_offset: lerp(start.palette_offset, end.palette_offset),
+        })
+    }
+}
+
+// A Catmull-Rom spline from points[1] to points[2], worked out as offsets from points[1] so that
+// when along is tiny the result still has all the digits of points[1]
+fn spline(points: [&ComplexDecimal; 4], along: f64, scale: u32) -> ComplexDecimal {
+    let (u, u2, u3) = (along, along * along, along * along * along);
+    let weights = [
+        (-u + 2.0 * u2 - u3) / 2.0,
+        0.0,
+        (u + 4.0 * u2 - 3.0 * u3) / 2.0,
+        (u3 - u2) / 2.0,
+    ];
+    let part = |values: [&Decimal; 4]| {
+        let mut total = values[1].with_scale(scale);
+        for (value, weight) in values.into_iter().zip(weights) {
+            let offset = value - values[1];
+            total = &total + &(&offset * &Decimal::from_f64(weight, scale));
+        }
+        total.with_scale(scale).normalised()
+    };
+    ComplexDecimal::new(
+        part(points.map(|point| &point.re)),
+        part(points.map(|point| &point.im)),
+    )
+}
+
+// Written one keyframe to a line as "frame re im zoom iterations palette-offset",
+// with anything after a # ignored
+impl FromStr for Animation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keyframes = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || {
+                format!(
+                    "line {} should be \"frame re im zoom iterations palette-offset\"",
+                    number + 1
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [frame, re, im, zoom, iterations, palette_offset] = fields[..] else {
+                return Err(invalid());
+            };
+            let zoom: f64 = zoom.parse().map_err(|_| invalid())?;
+            if !(zoom > 0.0 && zoom.is_finite()) {
+                return Err(format!("the zoom on line {} has to be above 0", number + 1));
+            }
+            keyframes.push(Keyframe {
+                frame: frame.parse().map_err(|_| invalid())?,
+                centre: ComplexDecimal::new(
+                    re.parse().map_err(|_| invalid())?,
+                    im.parse().map_err(|_| invalid())?,
+                ),
+                zoom,
+                max_iterations: iterations.parse().map_err(|_| invalid())?,
+                palette_offset: palette_offset.parse().map_err(|_| invalid())?,
+            });
+        }
+        Ok(Animation::new(keyframes))
+    }
+}
+
+impl std::fmt::Display for Animation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# frame re im zoom iterations palette-offset")?;
+        for keyframe in &self.keyframes {
+            writeln!(
+                f,
+                "{} {} {} {:e} {} {}",
+                keyframe.frame,
+                keyframe.centre.re,
+                keyframe.centre.im,
+                keyframe.zoom,
+                keyframe.max_iterations,
+                keyframe.palette_offset
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Save an animation as a GIF or animated PNG if the path is for one, otherwise as numbered PNGs
+// in a folder, see save_animated and render_frames
+pub fn export_animation<F: Formula + Clone>(
+    animation: &Animation,
+    base: &RenderParams<F>,
+    colours: &[(u8, u8, u8)],
+    path: &Path,
+    playback: Playback,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Result<(), String> {
+    match AnimatedFormat::from_path(path) {
+        Some(_) => save_animated(
+            animation, base, colours, path, playback, cancelled, progress,
+        ),
+        None => render_frames(animation, base, colours, path, cancelled, progress),
+    }
+}
+
+// Render every frame of an animation into a GIF or animated PNG, which is written as it goes.
+// Progress is called with how many pixels of the whole animation are done
+pub fn save_animated<F: Formula + Clone>(
+    animation: &Animation,
+    base: &RenderParams<F>,
+    colours: &[(u8, u8, u8)],
+    path: &Path,
+    playback: Playback,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Result<(), String> {
+    let format = AnimatedFormat::from_path(path)
+        .ok_or_else(|| format!("couldn't save {}: it isn't a .gif or .png", path.display()))?;
+    // written under another name first, so a file that's there is a whole one
+    let mut unfinished = path.as_os_str().to_owned();
+    unfinished.push(".part");
+    let unfinished = PathBuf::from(unfinished);
+    let pixels = base.width * base.height;
+    let written = (|| {
+        let mut writer = AnimatedWriter::create(
+            &unfinished,
+            format,
+            (base.width, base.height),
+            animation.frames(),
+            playback,
+            colours,
+            base.colouring,
+        )?;
+        for frame in 0..animation.frames() {
+            let Some(view) = animation.view_at(frame) else {
+                break;
+            };
+            let pixels_before = frame * pixels;
+            let image = render_image(&view.params(base), colours, cancelled, |pixels_done| {
+                progress(pixels_before + pixels_done)
+            })
+            .ok_or("the export was cancelled")?;
+            writer.write_frame(&image)?;
+        }
+        writer.finish()
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&unfinished);
+        return written;
+    }
+    fs::rename(&unfinished, path)
+        .map_err(|error| format!("couldn't write {}: {}", unfinished.display(), error))
+}
+
+// where a frame of an animation gets saved in a folder
+pub fn frame_path(folder: &Path, frame: u64) -> PathBuf {
+    folder.join(format!("frame{:05}.png", frame))
+}
+
+// whether a file is a frame from frame_path, or one that was still being rendered
+fn is_frame_path(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let number = name
+        .strip_prefix("frame")
+        .and_then(|name| name.strip_suffix(".png.part").or(name.strip_suffix(".png")));
+    number.is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// Render every frame of an animation into a folder as numbered PNGs, with base giving the size
+// and everything else the keyframes don't. The keyframes get saved alongside them. Frames that are
+// already there are skipped, so an animation that got interrupted carries on where it stopped,
+// unless they're from some other animation, in which case they all get deleted first.
+// Progress is called with how many pixels of the whole animation are done
+pub fn render_frames<F: Formula + Clone>(
+    animation: &Animation,
+    base: &RenderParams<F>,
+    colours: &[(u8, u8, u8)],
+    folder: &Path,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Result<(), String> {
+    let io_error = |path: &Path| {
+        let path = path.display().to_string();
+        move |error: std::io::Error| format!("couldn't write {}: {}", path, error)
+    };
+    fs::create_dir_all(folder).map_err(io_error(folder))?;
+    // Everything the frames depend on, like the view.txt of a PNG exported in strips. The parts
+    // of base the keyframes replace are left out, so the view the explorer happens to be at
+    // when an animation gets carried on doesn't matter
+    let rest = RenderParams {
+        centre: ComplexDecimal::default(),
+        resolution: Decimal::default(),
+        max_iterations: 0,
+        palette_offset: 0.0,
+        ..base.clone()
+    };
+    let view = format!("{}{:?}\n{:?}\n", animation, rest, colours);
+    let view_path = folder.join("view.txt");
+    if fs::read_to_string(&view_path).ok().as_deref() != Some(view.as_str()) {
+        // the folder could have anything else in it too, so only the frames go
+        let entries = fs::read_dir(folder).map_err(io_error(folder))?;
+        for entry in entries {
+            let path = entry.map_err(io_error(folder))?.path();
+            if is_frame_path(&path) {
+                fs::remove_file(&path).map_err(io_error(&path))?;
+            }
+        }
+        fs::write(&view_path, &view).map_err(io_error(&view_path))?;
+    }
+    let keyframes_path = folder.join("keyframes.txt");
+    fs::write(&keyframes_path, animation.to_string()).map_err(io_error(&keyframes_path))?;
+    let pixels = base.width * base.height;
+    for frame in 0..animation.frames() {
+        let path = frame_path(folder, frame);
+        let pixels_before = frame * pixels;
+        if path.exists() {
+            progress(pixels_before + pixels);
+            continue;
+        }
+        let Some(view) = animation.view_at(frame) else {
+            break;
+        };
+        // rendered under another name first, so any frame that's there is a whole one
+        let mut unfinished = path.clone().into_os_string();
+        unfinished.push(".part");
+        let unfinished = PathBuf::from(unfinished);
+        export_image(
+            &view.params(base),
+            colours,
+            &unfinished,
+            ImageFormat::Png,
+            cancelled,
+            |pixels_done| progress(pixels_before + pixels_done),
+        )?;
+        fs::rename(&unfinished, &path).map_err(io_error(&unfinished))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colours::DEFAULT_COLOURS;
+
+    fn cycle(palette_offset: f64) -> Animation {
+        Animation::palette_cycle(
+            Keyframe {
+                frame: 0,
+                centre: ComplexDecimal::new("-0.5".parse().unwrap(), Decimal::zero(0)),
+                zoom: 1.0,
+                max_iterations: 50,
+                palette_offset,
+            },
+            3,
+        )
+    }
+
+    #[test]
+    fn frames_from_other_animations_arent_reused() {
+        let folder = std::env::temp_dir().join(format!("mandelbrot-frames-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        let base = RenderParams::<crate::backend::Mandelbrot> {
+            width: 16,
+            height: 12,
+            ..Default::default()
+        };
+        let render = |animation: &Animation, base: &RenderParams| {
+            render_frames(
+                animation,
+                base,
+                &DEFAULT_COLOURS,
+                &folder,
+                &AtomicBool::new(false),
+                |_| {},
+            )
+            .unwrap()
+        };
+        let frame = frame_path(&folder, 1);
+        let mark = |frame: &Path| fs::write(frame, "not a frame").unwrap();
+        let marked = |frame: &Path| fs::read(frame).unwrap() == b"not a frame";
+
+        render(&cycle(0.0), &base);
+        fs::write(folder.join("notes.txt"), "mine").unwrap();
+        // the same animation carries on from the frames that are there, wherever the base is
+        mark(&frame);
+        let elsewhere = RenderParams {
+            centre: ComplexDecimal::new("0.25".parse().unwrap(), Decimal::zero(0)),
+            max_iterations: 1000,
+            ..base.clone()
+        };
+        render(&cycle(0.0), &elsewhere);
+        assert!(marked(&frame));
+        // but a different one, or the same one rendered differently, starts again
+        render(&cycle(0.5), &base);
+        assert!(!marked(&frame));
+        mark(&frame);
+        render(
+            &cycle(0.5),
+            &RenderParams {
+                bailout: 4.0,
+                ..base.clone()
+            },
+        );
+        assert!(!marked(&frame));
+        assert!(frame_path(&folder, 2).exists());
+        assert_eq!(
+            fs::read_to_string(folder.join("notes.txt")).unwrap(),
+            "mine"
+        );
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -1,5 +1,331 @@
+use crate::colours::{distance_shade, palette_colour, InteriorColouring};
+use crate::precision::Decimal;
+use crate::simd::Kernel;
 use num::Complex;
 use rayon::prelude::*;
+use std::sync::Arc;
+
+// One step of an escape time fractal, the next z from the current z and c
+pub trait Formula: std::fmt::Debug + Send + Sync {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64>;
+
+    // whether this is exactly z^2 + c, as perturbation, the series approximation
+    // and the cardioid/bulb checks are only worked out for that
+    fn is_mandelbrot(&self) -> bool {
+        false
+    }
+
+    // the power of z that takes over once z gets big, for the continuous iteration count
+    fn degree(&self) -> f64 {
+        2.0
+    }
+
+    // The derivative of the next z, given the derivative dz of the current z and dc of c.
+    // Following dz/dc this way is what the distance estimate needs, dc is 1 for the mandelbrot
+    // set and 0 for julia sets. This default works it out numerically, which is fine for any
+    // formula that's smooth in z and c, but formulas that know their own derivative should use it
+    fn derivative(
+        &self,
+        z: Complex<f64>,
+        c: Complex<f64>,
+        dz: Complex<f64>,
+        dc: Complex<f64>,
+    ) -> Complex<f64> {
+        let step = 1e-7 * (1.0 + z.norm());
+        let next = self.iterate(z, c);
+        let by_z = (self.iterate(z + step, c) - next) / step;
+        let by_c = (self.iterate(z, c + step) - next) / step;
+        by_z * dz + by_c * dc
+    }
+}
+
+impl<F: Formula + ?Sized> Formula for Arc<F> {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        (**self).iterate(z, c)
+    }
+
+    fn derivative(
+        &self,
+        z: Complex<f64>,
+        c: Complex<f64>,
+        dz: Complex<f64>,
+        dc: Complex<f64>,
+    ) -> Complex<f64> {
+        (**self).derivative(z, c, dz, dc)
+    }
+
+    fn is_mandelbrot(&self) -> bool {
+        (**self).is_mandelbrot()
+    }
+
+    fn degree(&self) -> f64 {
+        (**self).degree()
+    }
+}
+
+// z^2 + c
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Mandelbrot;
+
+impl Formula for Mandelbrot {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        z * z + c
+    }
+
+    fn is_mandelbrot(&self) -> bool {
+        true
+    }
+
+    fn derivative(
+        &self,
+        z: Complex<f64>,
+        _c: Complex<f64>,
+        dz: Complex<f64>,
+        dc: Complex<f64>,
+    ) -> Complex<f64> {
+        z * dz * 2.0 + dc
+    }
+}
+
+// z^n + c for a whole number n
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Multibrot(pub u32);
+
+impl Formula for Multibrot {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        z.powu(self.0) + c
+    }
+
+    fn degree(&self) -> f64 {
+        self.0 as f64
+    }
+
+    fn derivative(
+        &self,
+        z: Complex<f64>,
+        _c: Complex<f64>,
+        dz: Complex<f64>,
+        dc: Complex<f64>,
+    ) -> Complex<f64> {
+        z.powu(self.0 - 1) * dz * self.0 as f64 + dc
+    }
+}
+
+// z^n + c for any real n, which is much slower as it goes through polar form
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RealMultibrot(pub f64);
+
+impl Formula for RealMultibrot {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        z.powf(self.0) + c
+    }
+
+    fn degree(&self) -> f64 {
+        self.0
+    }
+
+    fn derivative(
+        &self,
+        z: Complex<f64>,
+        _c: Complex<f64>,
+        dz: Complex<f64>,
+        dc: Complex<f64>,
+    ) -> Complex<f64> {
+        // powf goes through the log of z, which doesn't exist at 0
+        if z == Complex::new(0.0, 0.0) {
+            return dc;
+        }
+        z.powf(self.0 - 1.0) * dz * self.0 + dc
+    }
+}
+
+// (|re z| + i|im z|)^2 + c
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct BurningShip;
+
+impl Formula for BurningShip {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let z = Complex::new(z.re.abs(), z.im.abs());
+        z * z + c
+    }
+}
+
+// conj(z)^2 + c, also known as the mandelbar set
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Tricorn;
+
+impl Formula for Tricorn {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let z = z.conj();
+        z * z + c
+    }
+}
+
+// |re(z^2)| + i im(z^2) + c
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Celtic;
+
+impl Formula for Celtic {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let z = z * z;
+        Complex::new(z.re.abs(), z.im) + c
+    }
+}
+
+// |re(z^2)| + i|im(z^2)| + c, celtic and burning ship combined
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Buffalo;
+
+impl Formula for Buffalo {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let z = z * z;
+        Complex::new(z.re.abs(), z.im.abs()) + c
+    }
+}
+
+// Shortcuts for points that never escape, so they don't have to be iterated all the way to
+// max_iterations. None of them change any iteration counts, they only get there quicker
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct InteriorChecks {
+    // closed form test for the main cardioid, only for z^2 + c
+    pub cardioid: bool,
+    // closed form test for the period 2 bulb, only for z^2 + c
+    pub bulb: bool,
+    // stop once the orbit lands exactly on an earlier value of z (Brent's algorithm),
+    // as it will then go round the same cycle forever
+    pub periodicity: bool,
+}
+
+impl InteriorChecks {
+    pub const NONE: InteriorChecks = InteriorChecks {
+        cardioid: false,
+        bulb: false,
+        periodicity: false,
+    };
+
+    pub const ALL: InteriorChecks = InteriorChecks {
+        cardioid: true,
+        bulb: true,
+        periodicity: true,
+    };
+}
+
+// How the points of a plane get turned into colours
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Colouring {
+    // bands of the palette by how many iterations each point took to escape
+    #[default]
+    EscapeTime,
+    // shaded by how far each point is from the set compared to the size of a pixel,
+    // which draws the thin filaments that escape time colouring washes out
+    Distance,
+    // escape time bands, darkened close to the set
+    DistanceBands,
+}
+
+impl Colouring {
+    pub const ALL: [Colouring; 3] = [
+        Colouring::EscapeTime,
+        Colouring::Distance,
+        Colouring::DistanceBands,
+    ];
+}
+
+impl std::fmt::Display for Colouring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Colouring::EscapeTime => "Escape time",
+                Colouring::Distance => "Distance",
+                Colouring::DistanceBands => "Distance bands",
+            }
+        )
+    }
+}
+
+// How the pixels of a plane are laid out over the complex plane
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Projection {
+    // rows and columns of evenly spaced points, like looking at the plane straight on
+    #[default]
+    Flat,
+    // An exponential map (log-polar): each row is a ring around the centre, with the columns
+    // going once round it, and each row down is a ring smaller by a factor of e^(2pi / width)
+    // so the pixels stay square. The top row goes through the corners of the square the width of
+    // the plane, and the rows after it zoom in on the centre for as many decades as there are rows
+    // for. See exponential_map for turning that back into frames of a zoom
+    Exponential,
+}
+
+impl Projection {
+    // Where a pixel of an exponential map is compared to its centre, as a fraction of the width
+    // of the top row's square. Columns and rows can be part of the way between pixels
+    pub fn exponential_offset(column: f64, row: f64, width: u64) -> Complex<f64> {
+        let turn = std::f64::consts::TAU / width as f64;
+        Complex::from_polar(
+            (-row * turn).exp() / std::f64::consts::SQRT_2,
+            column * turn,
+        )
+    }
+}
+
+// What a point that never escapes settles into
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Interior {
+    // how many iterations it takes the orbit to come back round to the same z
+    pub period: u64,
+    // derivative of the whole cycle with respect to z, which is less than 1 in size
+    // for an attracting cycle and 0 at the centre of each bulb
+    pub multiplier: Complex<f64>,
+    // estimated distance to the edge of the set, only worked out for the mandelbrot set
+    pub distance: Option<f64>,
+}
+
+// longest cycle that gets looked for
+const MAX_PERIOD: u64 = 1024;
+
+// Where the orbit of a point ended up
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Escape {
+    pub iterations: u64,
+    // z after the last iteration, which is past the bailout radius if the point escaped
+    pub z: Complex<f64>,
+}
+
+impl Escape {
+    // The iteration count with a fractional part from how far past the bailout radius z got,
+    // so it changes smoothly across the plane instead of in bands. The fraction is measured in
+    // powers of the degree of the formula, so it runs from 0 to 1 between two whole iterations
+    pub fn continuous(&self, bailout: f64, degree: f64) -> f64 {
+        let past_bailout = self.z.norm().ln() / bailout.ln();
+        self.iterations as f64 + 1.0 - past_bailout.ln() / degree.ln()
+    }
+}
+
+// Everything about a point that its colour gets worked out from, so it can be coloured again
+// later without iterating it. See MandelbrotPlane::point_data
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PointData {
+    // the smooth iteration count, or None if the point never escaped
+    pub continuous: Option<f64>,
+    // the smooth iteration count out to the distance estimate's bailout radius, which is what
+    // distance bands colouring goes by
+    pub bands: Option<f64>,
+    pub z: Complex<f64>,
+    // the distance estimate in pixels
+    pub distance: f64,
+}
+
+// how far z has to get before it counts as escaped unless the plane is told otherwise,
+// anything much bigger than 2 makes the continuous iteration count a lot smoother
+pub const DEFAULT_BAILOUT: f64 = 256.0;
+
+// points need to get much further away than 2 before the distance estimate is any good,
+// so the bailout radius is raised to at least this when estimating it
+const MIN_DISTANCE_BAILOUT: f64 = 1e6;
+
 // A point on the complex plane
 #[derive(Debug, Copy, Clone)]
 pub struct MandelbrotPoint {
@@ -15,31 +341,499 @@ impl MandelbrotPoint {
         self.point
     }
 
-    // Return how many iterations it takes for the point to escape a circle of radius 4,
+    // Return how many iterations it takes for the point to escape a circle of radius 2,
     // and cut off at max_iterations if it does not escape
     pub fn iterations(self, max_iterations: u64) -> u64 {
+        self.iterations_with(&Mandelbrot, max_iterations)
+    }
+
+    // Same as iterations, but with any formula instead of z^2 + c
+    pub fn iterations_with<F: Formula>(self, formula: &F, max_iterations: u64) -> u64 {
+        self.escape_with(formula, max_iterations, 2.0, InteriorChecks::NONE)
+            .iterations
+    }
+
+    // Where the orbit of the point ends up with any escape radius,
+    // using the interior checks to skip points that never escape
+    pub fn escape_with<F: Formula>(
+        self,
+        formula: &F,
+        max_iterations: u64,
+        bailout: f64,
+        checks: InteriorChecks,
+    ) -> Escape {
+        if formula.is_mandelbrot()
+            && (checks.cardioid && self.in_main_cardioid()
+                || checks.bulb && self.in_period_2_bulb())
+        {
+            return Escape {
+                iterations: max_iterations,
+                z: Complex::new(0.0, 0.0),
+            };
+        }
+        escape_time(
+            formula,
+            Complex::new(0.0, 0.0),
+            self.point,
+            max_iterations,
+            bailout,
+            checks.periodicity,
+        )
+    }
+
+    // whether the point is inside the big heart shaped part of the mandelbrot set
+    pub fn in_main_cardioid(self) -> bool {
+        let x = self.point.re - 0.25;
+        let y_squared = self.point.im * self.point.im;
+        let q = x * x + y_squared;
+        q * (q + x) <= 0.25 * y_squared
+    }
+
+    // whether the point is inside the circle to the left of the main cardioid
+    pub fn in_period_2_bulb(self) -> bool {
+        let x = self.point.re + 1.0;
+        x * x + self.point.im * self.point.im <= 0.0625
+    }
+
+    // Same as iterations, but for the julia set of c,
+    // so the point is where z starts instead of being c
+    pub fn julia_iterations(self, c: Complex<f64>, max_iterations: u64) -> u64 {
+        self.julia_escape_with(&Mandelbrot, c, max_iterations, 2.0, false)
+            .iterations
+    }
+
+    pub fn julia_escape_with<F: Formula>(
+        self,
+        formula: &F,
+        c: Complex<f64>,
+        max_iterations: u64,
+        bailout: f64,
+        periodicity: bool,
+    ) -> Escape {
+        escape_time(formula, self.point, c, max_iterations, bailout, periodicity)
+    }
+
+    // Same as escape_with, but the point is an offset from the centre of the reference orbit.
+    // Whenever the offset grows bigger than the full value (a glitch), or the reference escapes
+    // before this point does, the offset gets rebased back onto the start of the reference orbit.
+    // A series approximation lets the first few iterations be skipped entirely
+    pub fn escape_perturbed(
+        self,
+        reference: &ReferenceOrbit,
+        series: Option<&SeriesApproximation>,
+        max_iterations: u64,
+        bailout: f64,
+    ) -> Escape {
+        let orbit = &reference.orbit;
+        let (mut iteration, mut dz) = match series {
+            Some(series) => (series.skip, series.approximate(self.point)),
+            None => (0, Complex::new(0.0, 0.0)),
+        };
+        let mut reference_iteration = iteration as usize;
+        let mut z = orbit[reference_iteration] + dz;
+        while iteration < max_iterations {
+            dz = (orbit[reference_iteration] * 2.0 + dz) * dz + self.point;
+            reference_iteration += 1;
+            iteration += 1;
+            z = orbit[reference_iteration] + dz;
+            if z.norm_sqr() > bailout * bailout {
+                break;
+            }
+            if z.norm_sqr() < dz.norm_sqr() || reference_iteration == orbit.len() - 1 {
+                dz = z;
+                reference_iteration = 0;
+            }
+        }
+        Escape {
+            iterations: iteration,
+            z,
+        }
+    }
+
+    // where the orbit ends up along with the estimated distance to the edge of the set, which
+    // is 0 for points that never escape. With julia set c, or None for the mandelbrot set
+    pub fn distance_with<F: Formula>(
+        self,
+        formula: &F,
+        julia: Option<Complex<f64>>,
+        max_iterations: u64,
+        bailout: f64,
+    ) -> (Escape, f64) {
+        // mandelbrot points start at 0 and move with c, julia points start at themselves
+        let (mut z, c, mut dz, dc) = match julia {
+            Some(c) => (
+                self.point,
+                c,
+                Complex::new(1.0, 0.0),
+                Complex::new(0.0, 0.0),
+            ),
+            None => (
+                Complex::new(0.0, 0.0),
+                self.point,
+                Complex::new(0.0, 0.0),
+                Complex::new(1.0, 0.0),
+            ),
+        };
         let mut iteration = 0;
-        let mut z = Complex::new(0.0, 0.0);
-        while z.norm_sqr() <= 4.0 && iteration < max_iterations {
-            // finding square of distance much faster than sqrt()ing
-            z = z * z + self.point;
-            iteration += 1
+        while z.norm_sqr() <= bailout * bailout && iteration < max_iterations {
+            dz = formula.derivative(z, c, dz, dc);
+            z = formula.iterate(z, c);
+            iteration += 1;
+        }
+        (
+            Escape {
+                iterations: iteration,
+                z,
+            },
+            distance_estimate(z, dz, iteration < max_iterations),
+        )
+    }
+
+    // same as distance_with for z^2 + c, but relative to a reference orbit like escape_perturbed
+    pub fn distance_perturbed(
+        self,
+        reference: &ReferenceOrbit,
+        series: Option<&SeriesApproximation>,
+        max_iterations: u64,
+        bailout: f64,
+    ) -> (Escape, f64) {
+        let orbit = &reference.orbit;
+        let (mut iteration, mut dz, mut derivative) = match series {
+            Some(series) => (
+                series.skip,
+                series.approximate(self.point),
+                series.derivative(self.point),
+            ),
+            None => (0, Complex::new(0.0, 0.0), Complex::new(0.0, 0.0)),
+        };
+        let mut reference_iteration = iteration as usize;
+        let mut z = orbit[reference_iteration] + dz;
+        while iteration < max_iterations {
+            derivative = z * derivative * 2.0 + 1.0;
+            dz = (orbit[reference_iteration] * 2.0 + dz) * dz + self.point;
+            reference_iteration += 1;
+            iteration += 1;
+            z = orbit[reference_iteration] + dz;
+            if z.norm_sqr() > bailout * bailout {
+                break;
+            }
+            if z.norm_sqr() < dz.norm_sqr() || reference_iteration == orbit.len() - 1 {
+                dz = z;
+                reference_iteration = 0;
+            }
+        }
+        (
+            Escape {
+                iterations: iteration,
+                z,
+            },
+            distance_estimate(z, derivative, iteration < max_iterations),
+        )
+    }
+
+    // The cycle a point that never escapes settles into, or None if it escapes after all.
+    // The orbit is run for settle iterations to get close to the cycle, the period is then
+    // guessed from when it comes back closest to where it was, and Newton's method finds the
+    // exact point on the cycle to get the multiplier from
+    pub fn interior_with<F: Formula>(
+        self,
+        formula: &F,
+        julia: Option<Complex<f64>>,
+        settle: u64,
+    ) -> Option<Interior> {
+        let (mut z, c) = match julia {
+            Some(c) => (self.point, c),
+            None => (Complex::new(0.0, 0.0), self.point),
+        };
+        for _ in 0..settle {
+            z = formula.iterate(z, c);
+            if z.norm_sqr() > 4.0 {
+                return None;
+            }
+        }
+
+        let start = z;
+        let mut closest = (f64::INFINITY, 1);
+        for period in 1..=MAX_PERIOD.min(settle.max(1)) {
+            z = formula.iterate(z, c);
+            let distance = (z - start).norm_sqr();
+            if distance < closest.0 {
+                closest = (distance, period);
+            }
+        }
+
+        // an orbit with a negative multiplier comes back closer after twice the period,
+        // so try all the factors of the guess before the guess itself
+        let guess = closest.1;
+        (1..=guess)
+            .filter(|period| guess % period == 0)
+            .find_map(|period| {
+                let (z, multiplier) = cycle(formula, start, c, period)?;
+                Some(Interior {
+                    period,
+                    multiplier,
+                    distance: (julia.is_none() && formula.is_mandelbrot())
+                        .then(|| interior_distance(z, c, period)),
+                })
+            })
+    }
+}
+
+// Newton's method on f^period(z) - z, giving the point on the cycle along with the multiplier
+// if it finds an attracting cycle of exactly that period
+fn cycle<F: Formula>(
+    formula: &F,
+    mut z: Complex<f64>,
+    c: Complex<f64>,
+    period: u64,
+) -> Option<(Complex<f64>, Complex<f64>)> {
+    let no_dc = Complex::new(0.0, 0.0);
+    for _ in 0..16 {
+        let mut w = z;
+        let mut derivative = Complex::new(1.0, 0.0);
+        for _ in 0..period {
+            derivative = formula.derivative(w, c, derivative, no_dc);
+            w = formula.iterate(w, c);
+        }
+        let step = (w - z) / (derivative - 1.0);
+        if !step.re.is_finite() || !step.im.is_finite() {
+            return None;
+        }
+        z -= step;
+        if step.norm() <= 1e-12 * (1.0 + z.norm()) {
+            return (derivative.norm() < 1.0).then_some((z, derivative));
+        }
+    }
+    None
+}
+
+// distance to the edge of the mandelbrot set from inside one of its bulbs, from the
+// derivatives of z^2 + c over one cycle starting at the point z on the cycle
+fn interior_distance(mut z: Complex<f64>, c: Complex<f64>, period: u64) -> f64 {
+    let mut dz = Complex::new(1.0, 0.0);
+    let mut dc = Complex::new(0.0, 0.0);
+    let mut dzdz = Complex::new(0.0, 0.0);
+    let mut dcdz = Complex::new(0.0, 0.0);
+    for _ in 0..period {
+        dcdz = (dc * dz + z * dcdz) * 2.0;
+        dzdz = (dz * dz + z * dzdz) * 2.0;
+        dc = z * dc * 2.0 + 1.0;
+        dz = z * dz * 2.0;
+        z = z * z + c;
+    }
+    (1.0 - dz.norm_sqr()) / (dcdz + dzdz * dc / (-dz + 1.0)).norm()
+}
+
+// |z| ln|z| / |dz/dc|, roughly how far away the edge of the set is once z has escaped
+fn distance_estimate(z: Complex<f64>, derivative: Complex<f64>, escaped: bool) -> f64 {
+    if !escaped {
+        return 0.0;
+    }
+    let distance = z.norm() * z.norm().ln() / derivative.norm();
+    // an infinite derivative is still right next to the set
+    if distance.is_nan() {
+        0.0
+    } else {
+        distance
+    }
+}
+
+// how many times the formula can be applied to z before it escapes past the bailout radius
+fn escape_time<F: Formula>(
+    formula: &F,
+    mut z: Complex<f64>,
+    c: Complex<f64>,
+    max_iterations: u64,
+    bailout: f64,
+    periodicity: bool,
+) -> Escape {
+    let mut iteration = 0;
+    // Brent's algorithm, z is compared against a saved value that gets replaced
+    // after 1, 2, 4, 8... iterations, so any cycle is eventually caught
+    let mut saved = z;
+    let mut cycle_limit = 1;
+    let mut cycle_length = 0;
+    while z.norm_sqr() <= bailout * bailout && iteration < max_iterations {
+        // finding square of distance much faster than sqrt()ing
+        z = formula.iterate(z, c);
+        iteration += 1;
+        if periodicity {
+            if z == saved {
+                return Escape {
+                    iterations: max_iterations,
+                    z,
+                };
+            }
+            cycle_length += 1;
+            if cycle_length == cycle_limit {
+                saved = z;
+                cycle_limit *= 2;
+                cycle_length = 0;
+            }
+        }
+    }
+    Escape {
+        iterations: iteration,
+        z,
+    }
+}
+
+// The orbit of the centre of the view, calculated at high precision and then stored as f64s,
+// which every other point can then be iterated relative to
+#[derive(Debug, Clone)]
+pub struct ReferenceOrbit {
+    centre: Complex<f64>,
+    orbit: Vec<Complex<f64>>,
+}
+
+impl ReferenceOrbit {
+    pub fn new(centre_re: &Decimal, centre_im: &Decimal, max_iterations: u64) -> ReferenceOrbit {
+        let scale = centre_re.scale().max(centre_im.scale());
+        let two = Decimal::from_f64(2.0, scale);
+        let mut re = Decimal::zero(scale);
+        let mut im = Decimal::zero(scale);
+        let mut orbit = vec![Complex::new(0.0, 0.0)];
+        // stop once the reference escapes, points are rebased when they run off the end
+        while (orbit.len() as u64) <= max_iterations && orbit[orbit.len() - 1].norm_sqr() <= 4.0 {
+            let re_squared = &re * &re;
+            let im_squared = &im * &im;
+            im = &(&(&two * &re) * &im) + centre_im;
+            re = &(&re_squared - &im_squared) + centre_re;
+            orbit.push(Complex::new(re.to_f64(), im.to_f64()));
+        }
+        ReferenceOrbit {
+            centre: Complex::new(centre_re.to_f64(), centre_im.to_f64()),
+            orbit,
+        }
+    }
+
+    pub fn centre(&self) -> Complex<f64> {
+        self.centre
+    }
+
+    pub fn len(&self) -> usize {
+        self.orbit.len()
+    }
+}
+
+// how close the series approximation has to stay to the properly iterated offsets
+const SERIES_TOLERANCE: f64 = 1e-4;
+
+// Approximates the offset from the reference orbit after some number of iterations as a cubic
+// in the offset of c, so every point in a plane can jump straight past those iterations
+#[derive(Debug, Copy, Clone)]
+pub struct SeriesApproximation {
+    skip: u64,
+    coefficients: [Complex<f64>; 3],
+}
+
+impl SeriesApproximation {
+    // The probes are offsets that get iterated properly alongside the series to check it,
+    // usually the corners and edges of a plane. The approximation is only used up to the last
+    // iteration where every probe still agrees with it and the cubic term is still negligible
+    pub fn new(
+        reference: &ReferenceOrbit,
+        probes: &[Complex<f64>],
+        max_iterations: u64,
+    ) -> SeriesApproximation {
+        let orbit = &reference.orbit;
+        let radius = probes.iter().map(|probe| probe.norm()).fold(0.0, f64::max);
+        let mut series = SeriesApproximation {
+            skip: 0,
+            coefficients: [Complex::new(0.0, 0.0); 3],
+        };
+        let [mut a, mut b, mut c] = series.coefficients;
+        let mut offsets = vec![Complex::new(0.0, 0.0); probes.len()];
+        // points need at least one more step along the reference before they would be rebased
+        let limit = max_iterations.min(orbit.len().saturating_sub(2) as u64);
+        for n in 0..limit as usize {
+            let z = orbit[n];
+            (a, b, c) = (
+                z * 2.0 * a + 1.0,
+                z * 2.0 * b + a * a,
+                z * 2.0 * c + a * b * 2.0,
+            );
+            for (offset, probe) in offsets.iter_mut().zip(probes) {
+                *offset = (z * 2.0 + *offset) * *offset + probe;
+            }
+
+            let candidate = SeriesApproximation {
+                skip: n as u64 + 1,
+                coefficients: [a, b, c],
+            };
+            let finite = [a, b, c].iter().all(|term| term.is_finite());
+            let truncation_ok = c.norm() * radius <= SERIES_TOLERANCE * b.norm();
+            let probes_ok = probes.iter().zip(&offsets).all(|(probe, offset)| {
+                (orbit[n + 1] + offset).norm_sqr() <= 4.0
+                    && (candidate.approximate(*probe) - offset).norm()
+                        <= SERIES_TOLERANCE * offset.norm()
+            });
+            if !(finite && truncation_ok && probes_ok) {
+                break;
+            }
+            series = candidate;
         }
-        iteration
+        series
+    }
+
+    // how many iterations every point gets to skip
+    pub fn skip(&self) -> u64 {
+        self.skip
+    }
+
+    // the offset from the reference orbit after skip iterations
+    pub fn approximate(&self, point: Complex<f64>) -> Complex<f64> {
+        let [a, b, c] = self.coefficients;
+        ((c * point + b) * point + a) * point
+    }
+
+    // the derivative of approximate, which is also dz/dc after skip iterations
+    pub fn derivative(&self, point: Complex<f64>) -> Complex<f64> {
+        let [a, b, c] = self.coefficients;
+        (c * point * 3.0 + b * 2.0) * point + a
     }
 }
 
+// How the escape time of each point in a plane is calculated
+#[derive(Debug, Clone, Default)]
+pub enum Algorithm {
+    // iterate every point directly with f64s, which breaks down past a zoom of about 1e15
+    #[default]
+    Direct,
+    // iterate every point as an offset from a reference orbit,
+    // the bounds of the plane are then relative to the centre of the reference.
+    // This is always z^2 + c, so only formulas that support perturbation should use it
+    Perturbation {
+        reference: Arc<ReferenceOrbit>,
+        series: Option<SeriesApproximation>,
+    },
+}
+
 // A section of the complex plane, the width and height specifying how many individual
 // mandelbrot points are rendered in the plane
-#[derive(Debug, Copy, Clone)]
-pub struct MandelbrotPlane {
+#[derive(Debug, Clone)]
+pub struct MandelbrotPlane<F: Formula = Mandelbrot> {
     re_min: f64,
     re_max: f64,
     im_min: f64,
     im_max: f64,
     width: u64,
     height: u64,
+    // where the rows of this plane start and how many there are in the whole plane,
+    // for when it's only part of a bigger one
+    first_row: u64,
+    total_height: u64,
     max_iterations: u64,
+    algorithm: Algorithm,
+    julia: Option<Complex<f64>>,
+    formula: F,
+    interior_checks: InteriorChecks,
+    colouring: Colouring,
+    interior_colouring: InteriorColouring,
+    bailout: f64,
+    palette_offset: f64,
+    projection: Projection,
+    kernel: Kernel,
 }
 
 impl MandelbrotPlane {
@@ -59,124 +853,644 @@ impl MandelbrotPlane {
             im_max,
             width,
             height,
+            first_row: 0,
+            total_height: height,
             max_iterations,
+            algorithm: Algorithm::Direct,
+            julia: None,
+            formula: Mandelbrot,
+            interior_checks: InteriorChecks::NONE,
+            colouring: Colouring::EscapeTime,
+            interior_colouring: InteriorColouring::Black,
+            bailout: DEFAULT_BAILOUT,
+            palette_offset: 0.0,
+            projection: Projection::Flat,
+            kernel: Kernel::Scalar,
+        }
+    }
+}
+
+impl<F: Formula> MandelbrotPlane<F> {
+    // iterate a different formula, which goes back to iterating directly
+    // if the new formula doesn't support perturbation
+    pub fn with_formula<G: Formula>(self, formula: G) -> MandelbrotPlane<G> {
+        MandelbrotPlane {
+            re_min: self.re_min,
+            re_max: self.re_max,
+            im_min: self.im_min,
+            im_max: self.im_max,
+            width: self.width,
+            height: self.height,
+            first_row: self.first_row,
+            total_height: self.total_height,
+            max_iterations: self.max_iterations,
+            algorithm: if formula.is_mandelbrot() {
+                self.algorithm
+            } else {
+                Algorithm::Direct
+            },
+            julia: self.julia,
+            formula,
+            interior_checks: self.interior_checks,
+            colouring: self.colouring,
+            interior_colouring: self.interior_colouring,
+            bailout: self.bailout,
+            palette_offset: self.palette_offset,
+            projection: self.projection,
+            kernel: self.kernel,
+        }
+    }
+
+    // how far from 0 z has to get before a point counts as escaped
+    pub fn with_bailout(self, bailout: f64) -> MandelbrotPlane<F> {
+        MandelbrotPlane { bailout, ..self }
+    }
+
+    // how far along the palette the colours start, see colours::palette_colour
+    pub fn with_palette_offset(self, palette_offset: f64) -> MandelbrotPlane<F> {
+        MandelbrotPlane {
+            palette_offset,
+            ..self
         }
     }
 
-    pub fn width(self) -> u64 {
+    pub fn with_projection(self, projection: Projection) -> MandelbrotPlane<F> {
+        MandelbrotPlane { projection, ..self }
+    }
+
+    pub fn with_colouring(self, colouring: Colouring) -> MandelbrotPlane<F> {
+        MandelbrotPlane { colouring, ..self }
+    }
+
+    pub fn with_interior_colouring(
+        self,
+        interior_colouring: InteriorColouring,
+    ) -> MandelbrotPlane<F> {
+        MandelbrotPlane {
+            interior_colouring,
+            ..self
+        }
+    }
+
+    pub fn with_interior_checks(self, interior_checks: InteriorChecks) -> MandelbrotPlane<F> {
+        MandelbrotPlane {
+            interior_checks,
+            ..self
+        }
+    }
+
+    // render the julia set of c instead, this always iterates directly
+    pub fn with_julia(self, c: Complex<f64>) -> MandelbrotPlane<F> {
+        MandelbrotPlane {
+            julia: Some(c),
+            algorithm: Algorithm::Direct,
+            ..self
+        }
+    }
+
+    pub fn with_algorithm(self, algorithm: Algorithm) -> MandelbrotPlane<F> {
+        MandelbrotPlane { algorithm, ..self }
+    }
+
+    // how to iterate rows of points at once, when the plane is simple enough for that
+    pub fn with_kernel(self, kernel: Kernel) -> MandelbrotPlane<F> {
+        MandelbrotPlane { kernel, ..self }
+    }
+
+    pub fn formula(&self) -> &F {
+        &self.formula
+    }
+
+    pub fn width(&self) -> u64 {
         self.width
     }
 
-    pub fn height(self) -> u64 {
+    pub fn height(&self) -> u64 {
         self.height
     }
 
-    pub fn re_max(self) -> f64 {
+    pub fn max_iterations(&self) -> u64 {
+        self.max_iterations
+    }
+
+    // the row of the whole plane this one starts at
+    pub fn first_row(&self) -> u64 {
+        self.first_row
+    }
+
+    pub fn re_max(&self) -> f64 {
         self.re_max
     }
 
-    pub fn re_min(self) -> f64 {
+    pub fn re_min(&self) -> f64 {
         self.re_min
     }
 
-    pub fn im_max(self) -> f64 {
+    pub fn im_max(&self) -> f64 {
         self.im_max
     }
 
-    pub fn im_min(self) -> f64 {
+    pub fn im_min(&self) -> f64 {
         self.im_min
     }
 
-    // Return a Vec with all the mandelbrot points in the plane (width*height),
-    // along with the number of iterations (escape time)
-    pub fn points_with_iterations(self) -> Vec<(MandelbrotPoint, u64)> {
-        let mut points = Vec::new();
-        let mut point;
-        // create list of all points in bounds specified
-        for real in 0..self.width {
-            for imaginary in 0..self.height {
-                point = MandelbrotPoint::new(Complex::new(
-                    (((self.re_max - self.re_min) / (self.width as f64)) * real as f64)
-                        + self.re_min,
-                    (((self.im_max - self.im_min) / (self.height as f64)) * imaginary as f64)
-                        + self.im_min,
-                ));
-                points.push((point, point.iterations(self.max_iterations)))
+    pub fn algorithm(&self) -> &Algorithm {
+        &self.algorithm
+    }
+
+    pub fn bailout(&self) -> f64 {
+        self.bailout
+    }
+
+    pub fn colouring(&self) -> Colouring {
+        self.colouring
+    }
+
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    // the width of a single pixel on the complex plane
+    pub fn pixel_size(&self) -> f64 {
+        (self.re_max - self.re_min) / self.width as f64
+    }
+
+    // the width of the pixel a point is in, which in an exponential map shrinks towards the centre
+    fn pixel_size_at(&self, point: MandelbrotPoint) -> f64 {
+        match self.projection {
+            Projection::Flat => self.pixel_size(),
+            Projection::Exponential => {
+                (point.point - self.centre()).norm() * std::f64::consts::TAU / self.width as f64
+            }
+        }
+    }
+
+    // the middle of the plane, which is an offset from the reference with perturbation
+    fn centre(&self) -> Complex<f64> {
+        Complex::new(
+            (self.re_min + self.re_max) / 2.0,
+            (self.im_min + self.im_max) / 2.0,
+        )
+    }
+
+    // checks a series approximation against the corners and edges of the plane,
+    // this only does anything when using perturbation
+    pub fn with_series_approximation(self) -> MandelbrotPlane<F> {
+        let Algorithm::Perturbation { reference, .. } = &self.algorithm else {
+            return self;
+        };
+        let mut probes = Vec::new();
+        for re in [self.re_min, (self.re_min + self.re_max) / 2.0, self.re_max] {
+            for im in [self.im_min, (self.im_min + self.im_max) / 2.0, self.im_max] {
+                probes.push(Complex::new(re, im))
+            }
+        }
+        let series = SeriesApproximation::new(reference, &probes, self.max_iterations);
+        let reference = reference.clone();
+        self.with_algorithm(Algorithm::Perturbation {
+            reference,
+            series: Some(series),
+        })
+    }
+
+    // how many iterations the series approximation lets every point skip
+    pub fn skipped_iterations(&self) -> u64 {
+        match &self.algorithm {
+            Algorithm::Perturbation {
+                series: Some(series),
+                ..
+            } => series.skip(),
+            _ => 0,
+        }
+    }
+
+    // escape time of a single point in the plane, using whichever algorithm the plane was set up with
+    pub fn iterations(&self, point: MandelbrotPoint) -> u64 {
+        self.escape(point).iterations
+    }
+
+    // where the orbit of a single point ends up
+    pub fn escape(&self, point: MandelbrotPoint) -> Escape {
+        if let Some(c) = self.julia {
+            return point.julia_escape_with(
+                &self.formula,
+                c,
+                self.max_iterations,
+                self.bailout,
+                self.interior_checks.periodicity,
+            );
+        }
+        match &self.algorithm {
+            Algorithm::Direct => point.escape_with(
+                &self.formula,
+                self.max_iterations,
+                self.bailout,
+                self.interior_checks,
+            ),
+            Algorithm::Perturbation { reference, series } => point.escape_perturbed(
+                reference,
+                series.as_ref(),
+                self.max_iterations,
+                self.bailout,
+            ),
+        }
+    }
+
+    // Where the orbits of lots of points end up, the same as calling escape on each of them.
+    // The kernel only knows plain z^2 + c iterated directly without periodicity checking,
+    // so anything else goes through escape one point at a time
+    pub fn escapes(&self, points: &[MandelbrotPoint]) -> Vec<Escape> {
+        if self.kernel == Kernel::Scalar
+            || !self.formula.is_mandelbrot()
+            || !matches!(self.algorithm, Algorithm::Direct)
+            || self.interior_checks.periodicity
+        {
+            return points.iter().map(|&point| self.escape(point)).collect();
+        }
+        let inside = Escape {
+            iterations: self.max_iterations,
+            z: Complex::new(0.0, 0.0),
+        };
+        let skipped = |point: &MandelbrotPoint| {
+            self.julia.is_none()
+                && (self.interior_checks.cardioid && point.in_main_cardioid()
+                    || self.interior_checks.bulb && point.in_period_2_bulb())
+        };
+        let starts: Vec<_> = points
+            .iter()
+            .filter(|point| !skipped(point))
+            .map(|point| match self.julia {
+                Some(c) => (point.point, c),
+                None => (Complex::new(0.0, 0.0), point.point),
+            })
+            .collect();
+        let mut escapes = self
+            .kernel
+            .escapes(&starts, self.max_iterations, self.bailout)
+            .into_iter();
+        points
+            .iter()
+            .map(|point| match skipped(point) {
+                true => inside,
+                false => escapes.next().unwrap_or(inside),
+            })
+            .collect()
+    }
+
+    // the smooth iteration count of a point, or None if it never escapes
+    pub fn continuous_iterations(&self, point: MandelbrotPoint) -> Option<f64> {
+        let escape = self.escape(point);
+        (escape.iterations < self.max_iterations)
+            .then(|| escape.continuous(self.bailout, self.formula.degree()))
+    }
+
+    // where the orbit of a single point ends up along with its distance estimate
+    pub fn distance(&self, point: MandelbrotPoint) -> (Escape, f64) {
+        let bailout = self.distance_bailout();
+        match &self.algorithm {
+            Algorithm::Perturbation { reference, series } if self.julia.is_none() => {
+                point.distance_perturbed(reference, series.as_ref(), self.max_iterations, bailout)
+            }
+            _ if self.julia.is_none()
+                && self.formula.is_mandelbrot()
+                && (self.interior_checks.cardioid && point.in_main_cardioid()
+                    || self.interior_checks.bulb && point.in_period_2_bulb()) =>
+            {
+                let escape = Escape {
+                    iterations: self.max_iterations,
+                    z: Complex::new(0.0, 0.0),
+                };
+                (escape, 0.0)
             }
+            _ => point.distance_with(&self.formula, self.julia, self.max_iterations, bailout),
+        }
+    }
+
+    // Where a point's orbit went along with its distance estimate, which is everything any
+    // colouring except the interior ones needs. The distance estimate goes out to a bigger
+    // bailout radius, so the point gets iterated twice to have both smooth iteration counts
+    pub fn point_data(&self, point: MandelbrotPoint) -> PointData {
+        let (escape, distance) = self.distance(point);
+        PointData {
+            continuous: self.continuous_iterations(point),
+            bands: (escape.iterations < self.max_iterations)
+                .then(|| escape.continuous(self.distance_bailout(), self.formula.degree())),
+            z: escape.z,
+            distance: distance / self.pixel_size_at(point),
+        }
+    }
+
+    fn distance_bailout(&self) -> f64 {
+        self.bailout.max(MIN_DISTANCE_BAILOUT)
+    }
+
+    // the cycle a point inside the set settles into. With perturbation this only has the
+    // low precision location of the point to go on, so it gets blocky at very deep zooms
+    pub fn interior(&self, point: MandelbrotPoint) -> Option<Interior> {
+        MandelbrotPoint::new(self.absolute(point)).interior_with(
+            &self.formula,
+            self.julia,
+            self.max_iterations,
+        )
+    }
+
+    // The colour of a single point. Everything that gets drawn goes through here,
+    // so the serial and parallel versions always come out exactly the same
+    pub fn colour(&self, point: MandelbrotPoint, colours: &[(u8, u8, u8)]) -> (u8, u8, u8) {
+        self.colour_and_iterations(point, colours).1
+    }
+
+    // The colour of a point along with how many iterations it took, which is max_iterations
+    // if it never escaped. Guessing goes by the iterations, see render_plane_guessing
+    pub fn colour_and_iterations(
+        &self,
+        point: MandelbrotPoint,
+        colours: &[(u8, u8, u8)],
+    ) -> (u64, (u8, u8, u8)) {
+        if self.colouring == Colouring::EscapeTime {
+            let escape = self.escape(point);
+            return (
+                escape.iterations,
+                self.escape_time_colour(point, escape, colours),
+            );
+        }
+        let (escape, distance) = self.distance(point);
+        if escape.iterations == self.max_iterations {
+            return (escape.iterations, self.interior_colour(point));
+        }
+        let shade = distance_shade(distance / self.pixel_size_at(point));
+        let scale = |channel: u8| (channel as f64 * shade).round() as u8;
+        let colour = match self.colouring {
+            Colouring::DistanceBands => {
+                // This can't use the shared continuous_iterations count. That one is
+                // smoothed at the plane's bailout, but this escape went on to the much
+                // bigger distance bailout, so its z and iteration count only make sense
+                // against that radius. Getting the shared count too would mean iterating
+                // the point a second time, like point_data has to
+                let continuous = escape.continuous(self.distance_bailout(), self.formula.degree());
+                let (r, g, b) = palette_colour(colours, continuous, self.palette_offset);
+                (scale(r), scale(g), scale(b))
+            }
+            _ => (scale(255), scale(255), scale(255)),
+        };
+        (escape.iterations, colour)
+    }
+
+    // Same as colour for each of a lot of points,
+    // which lets escape time colouring use the kernel to work several of them out at once
+    pub fn colours(
+        &self,
+        points: &[MandelbrotPoint],
+        colours: &[(u8, u8, u8)],
+    ) -> Vec<(u8, u8, u8)> {
+        if self.colouring != Colouring::EscapeTime {
+            return points
+                .iter()
+                .map(|&point| self.colour(point, colours))
+                .collect();
         }
         points
+            .iter()
+            .zip(self.escapes(points))
+            .map(|(&point, escape)| self.escape_time_colour(point, escape, colours))
+            .collect()
+    }
+
+    // the escape time colour of a point whose escape has already been worked out
+    pub fn escape_time_colour(
+        &self,
+        point: MandelbrotPoint,
+        escape: Escape,
+        colours: &[(u8, u8, u8)],
+    ) -> (u8, u8, u8) {
+        if escape.iterations < self.max_iterations {
+            palette_colour(
+                colours,
+                escape.continuous(self.bailout, self.formula.degree()),
+                self.palette_offset,
+            )
+        } else {
+            self.interior_colour(point)
+        }
+    }
+
+    // the colour of a point that never escaped
+    pub fn interior_colour(&self, point: MandelbrotPoint) -> (u8, u8, u8) {
+        if self.interior_colouring == InteriorColouring::Black {
+            return (0, 0, 0);
+        }
+        match self.interior(point) {
+            Some(interior) => self.interior_colouring.colour(
+                &interior,
+                interior
+                    .distance
+                    .map(|distance| distance / self.pixel_size_at(point)),
+            ),
+            None => (0, 0, 0),
+        }
+    }
+
+    // the actual (low precision) location of a point, as the point itself
+    // is only an offset when perturbation is being used
+    fn absolute(&self, point: MandelbrotPoint) -> Complex<f64> {
+        match &self.algorithm {
+            Algorithm::Direct => point.point,
+            Algorithm::Perturbation { reference, .. } => reference.centre + point.point,
+        }
+    }
+
+    // Return a Vec with all the mandelbrot points in the plane (width*height),
+    // along with the number of iterations (escape time)
+    pub fn points_with_iterations(&self) -> Vec<(MandelbrotPoint, u64)> {
+        self.points()
+            .into_iter()
+            .map(|point| (point, self.iterations(point)))
+            .collect()
     }
 
     // same, but in parallel :o
-    pub fn points_with_iterations_parallel(self) -> Vec<(MandelbrotPoint, u64)> {
+    pub fn points_with_iterations_parallel(&self) -> Vec<(MandelbrotPoint, u64)> {
+        self.points()
+            .into_par_iter()
+            .map(|point| (point, self.iterations(point)))
+            .collect()
+    }
+
+    // all the points in the plane with their smooth iteration count, None for points inside the set
+    pub fn points_with_continuous_iterations(&self) -> Vec<(MandelbrotPoint, Option<f64>)> {
+        self.points()
+            .into_iter()
+            .map(|point| (point, self.continuous_iterations(point)))
+            .collect()
+    }
+
+    // same, but in parallel
+    pub fn points_with_continuous_iterations_parallel(
+        &self,
+    ) -> Vec<(MandelbrotPoint, Option<f64>)> {
+        self.points()
+            .into_par_iter()
+            .map(|point| (point, self.continuous_iterations(point)))
+            .collect()
+    }
+
+    // all the points in the plane along with where they ended up and their distance estimate
+    pub fn points_with_distances(&self) -> Vec<(MandelbrotPoint, (Escape, f64))> {
+        self.points()
+            .into_iter()
+            .map(|point| (point, self.distance(point)))
+            .collect()
+    }
+
+    // same, but in parallel
+    pub fn points_with_distances_parallel(&self) -> Vec<(MandelbrotPoint, (Escape, f64))> {
+        self.points()
+            .into_par_iter()
+            .map(|point| (point, self.distance(point)))
+            .collect()
+    }
+
+    // create list of all points in bounds specified
+    fn points(&self) -> Vec<MandelbrotPoint> {
         let mut points = Vec::new();
-        let mut point;
-        // create list of all points in bounds specified
         for real in 0..self.width {
             for imaginary in 0..self.height {
-                point = MandelbrotPoint::new(Complex::new(
-                    (((self.re_max - self.re_min) / (self.width as f64)) * real as f64)
-                        + self.re_min,
-                    (((self.im_max - self.im_min) / (self.height as f64)) * imaginary as f64)
-                        + self.im_min,
-                ));
-                points.push(point)
+                points.push(self.point_at(real, imaginary))
             }
         }
         points
-            .into_par_iter()
-            .map(|point| (point, point.iterations(self.max_iterations)))
-            .collect()
+    }
+
+    // the point under pixel (x, y), x going along the real axis and y along the imaginary axis
+    pub fn point_at(&self, x: u64, y: u64) -> MandelbrotPoint {
+        self.sample_at(x as f64, y as f64)
+    }
+
+    // Part of the plane, from row top down height rows, which renders exactly the same as those
+    // rows of the whole plane. Anything to do with the reference orbit is kept as it is
+    pub fn rows(&self, top: u64, height: u64) -> MandelbrotPlane<F>
+    where
+        F: Clone,
+    {
+        MandelbrotPlane {
+            height,
+            first_row: self.first_row + top,
+            ..self.clone()
+        }
+    }
+
+    // the point part of the way between pixels, for taking more than one sample per pixel
+    pub fn sample_at(&self, x: f64, y: f64) -> MandelbrotPoint {
+        let y = y + self.first_row as f64;
+        match self.projection {
+            Projection::Flat => MandelbrotPoint::new(Complex::new(
+                (((self.re_max - self.re_min) / (self.width as f64)) * x) + self.re_min,
+                (((self.im_max - self.im_min) / (self.total_height as f64)) * y) + self.im_min,
+            )),
+            Projection::Exponential => MandelbrotPoint::new(
+                self.centre()
+                    + Projection::exponential_offset(x, y, self.width)
+                        * (self.re_max - self.re_min),
+            ),
+        }
     }
 
     // Instead of returning points with iterations,
     // this returns the colour for each point as an rgb tuple from a precomputed colour palette
     pub fn points_with_colours(
-        self,
+        &self,
         colours: &[(u8, u8, u8)],
     ) -> Vec<(MandelbrotPoint, (u8, u8, u8))> {
-        self.points_with_iterations()
+        self.points()
             .into_iter()
-            .map(|(point, iterations)| {
-                let i = iterations;
-                let point_coord = point.point;
-                let smoothed = point_coord.norm().log2();
-                let colour_i: usize =
-                    ((i as f64 + 10.0 - smoothed).sqrt() * 256.0).round() as usize % colours.len();
-                (
-                    point,
-                    if self.max_iterations == i {
-                        (0, 0, 0)
-                    } else {
-                        colours[colour_i]
-                    },
-                )
-            })
+            .map(|point| (point, self.colour(point, colours)))
             .collect()
     }
 
     // same but in parallel
     pub fn points_with_colours_parallel(
-        self,
+        &self,
         colours: &[(u8, u8, u8)],
     ) -> Vec<(MandelbrotPoint, (u8, u8, u8))> {
-        self.points_with_iterations_parallel()
+        self.points()
             .into_par_iter()
-            .map(|(point, iterations)| {
-                let i = iterations;
-                let point_coord = point.point;
-                let smoothed = point_coord.norm().log2();
-                let colour_i: usize =
-                    ((i as f64 + 10.0 - smoothed).sqrt() * 255.0).round() as usize % colours.len();
-                (
-                    point,
-                    if self.max_iterations == i {
-                        (0, 0, 0)
-                    } else {
-                        colours[colour_i]
-                    },
-                )
-            })
+            .map(|point| (point, self.colour(point, colours)))
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a grid over the whole set, plus rings just inside and outside the edges of the
+    // cardioid and the bulb where the closed form tests are closest to going wrong
+    fn test_points() -> Vec<MandelbrotPoint> {
+        let mut points: Vec<Complex<f64>> = (0..=150)
+            .flat_map(|x| {
+                (0..=130).map(move |y| Complex::new(-2.2 + x as f64 * 0.02, -1.3 + y as f64 * 0.02))
+            })
+            .collect();
+        for i in 0..720 {
+            let angle = Complex::from_polar(1.0, i as f64 * std::f64::consts::TAU / 720.0);
+            let cardioid = angle / 2.0 - angle * angle / 4.0;
+            let bulb = angle / 4.0 - 1.0;
+            for scale in [0.99, 0.999999, 1.0, 1.000001, 1.01] {
+                points.push(cardioid * scale);
+                points.push((bulb + 1.0) * scale - 1.0);
+            }
+        }
+        points.into_iter().map(MandelbrotPoint::new).collect()
+    }
+
+    #[test]
+    fn interior_checks_dont_change_iterations() {
+        let checks = [
+            InteriorChecks::ALL,
+            InteriorChecks {
+                cardioid: true,
+                ..InteriorChecks::NONE
+            },
+            InteriorChecks {
+                bulb: true,
+                ..InteriorChecks::NONE
+            },
+            InteriorChecks {
+                periodicity: true,
+                ..InteriorChecks::NONE
+            },
+        ];
+        let points = test_points();
+        for bailout in [2.0, DEFAULT_BAILOUT] {
+            for point in &points {
+                let naive = point.escape_with(&Mandelbrot, 500, bailout, InteriorChecks::NONE);
+                for checks in checks {
+                    let checked = point.escape_with(&Mandelbrot, 500, bailout, checks);
+                    assert_eq!(
+                        checked.iterations, naive.iterations,
+                        "{} with {:?}",
+                        point.point, checks
+                    );
+                    if naive.iterations < 500 {
+                        assert_eq!(checked.z, naive.z, "{} with {:?}", point.point, checks);
+                    }
+                }
+            }
+        }
+        // make sure both closed form tests actually got used
+        assert!(
+            points
+                .iter()
+                .filter(|point| point.in_main_cardioid())
+                .count()
+                > 1000
+        );
+        assert!(
+            points
+                .iter()
+                .filter(|point| point.in_period_2_bulb())
+                .count()
+                > 500
+        );
+    }
+}
@@ -0,0 +1,586 @@
+use crate::animated::Playback;
+use crate::animation::{export_animation, save_animated, Animation, Keyframe};
+use crate::backend::{Colouring, InteriorChecks, Projection, DEFAULT_BAILOUT};
+use crate::colours::InteriorColouring;
+use crate::exponential_map::render_zoom_frames;
+use crate::export::export_image;
+use crate::expression::Expression;
+use crate::iteration_data::{self, is_data_path, save_data};
+use crate::precision::{ComplexDecimal, Decimal};
+use crate::renderer::RenderParams;
+use crate::supersampling::{SamplePattern, Supersampling};
+use crate::{Colour, Fractal, CANVAS_SIZE};
+use clap::{Args, Parser, Subcommand};
+use image::ImageFormat;
+use num::Complex;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+// The command line. Without a subcommand this just opens the explorer
+#[derive(Debug, Parser)]
+#[command(about = "Explore the Mandelbrot set and other escape time fractals")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    #[command(about = "Render a view to a PNG without opening a window")]
+    Render(RenderArgs),
+    #[command(about = "Render a zoom between keyframes to a folder of numbered PNGs")]
+    Animate(AnimateArgs),
+    #[command(about = "Read the frames of a zoom out of an exponential map")]
+    ZoomFrames(ZoomFramesArgs),
+    #[command(about = "Colour a file of iteration data saved by render")]
+    Recolour(RecolourArgs),
+}
+
+// Everything about the view to render. The centre and zoom are the same as the ones the
+// explorer shows, and the zoom always fits the same part of the plane across the image
+// as the explorer does across its canvas, whatever the size of the image
+#[derive(Debug, Args)]
+pub struct RenderArgs {
+    #[arg(
+        long,
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Real part of the centre"
+    )]
+    re: Decimal,
+    #[arg(
+        long,
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Imaginary part of the centre"
+    )]
+    im: Decimal,
+    #[arg(long, default_value = "125", help = "Zoom, as shown in the explorer")]
+    zoom: Decimal,
+    #[arg(long, default_value_t = 1000, help = "Maximum number of iterations")]
+    iterations: u64,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "How far round the palette to start, from 0 to 1"
+    )]
+    palette_offset: f64,
+    #[arg(
+        long,
+        default_value = "flat",
+        value_parser = parse_projection,
+        help = "flat, or exponential for a map of a zoom into the centre. An exponential map \
+                goes round the centre across the width and zooms in down the height, one \
+                decade every 0.37 x width rows"
+    )]
+    projection: Projection,
+    #[arg(
+        short,
+        long,
+        default_value = "output.png",
+        help = "Where to save the image, PNGs are rendered in strips and can be resumed. \
+                A .mbdata file saves the iteration data of every pixel instead, for recolour"
+    )]
+    output: PathBuf,
+    #[arg(
+        long,
+        help = "Save a loop of this many frames going once round the palette instead, \
+                to a .gif or animated .png"
+    )]
+    cycle: Option<u64>,
+    #[command(flatten)]
+    playback: PlaybackArgs,
+    #[command(flatten)]
+    image: ImageArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct AnimateArgs {
+    #[arg(
+        help = "A file of keyframes, one to a line as \"frame re im zoom iterations palette-offset\""
+    )]
+    keyframes: PathBuf,
+    #[arg(
+        short,
+        long,
+        default_value = "frames",
+        help = "The folder to save the frames in, frames already there are skipped. \
+                A .gif or .png saves them all in one animated file instead"
+    )]
+    output: PathBuf,
+    #[command(flatten)]
+    playback: PlaybackArgs,
+    #[command(flatten)]
+    image: ImageArgs,
+}
+
+// How a GIF or animated PNG plays back
+#[derive(Debug, Args)]
+struct PlaybackArgs {
+    #[arg(
+        long,
+        default_value_t = 40,
+        help = "How long each frame of a GIF or animated PNG stays up, in milliseconds"
+    )]
+    delay: u32,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "How many times a GIF or animated PNG plays, 0 for forever"
+    )]
+    loops: u32,
+}
+
+impl PlaybackArgs {
+    fn playback(&self) -> Playback {
+        Playback {
+            delay: self.delay,
+            loops: self.loops,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ZoomFramesArgs {
+    #[arg(help = "An image rendered with --projection exponential")]
+    map: PathBuf,
+    #[arg(long, default_value_t = 1280)]
+    width: u64,
+    #[arg(long, default_value_t = 720)]
+    height: u64,
+    #[arg(
+        long,
+        default_value_t = 300,
+        help = "How many frames to take the zoom from the top of the map to the bottom"
+    )]
+    frames: u64,
+    #[arg(
+        short,
+        long,
+        default_value = "frames",
+        help = "The folder to save the frames in"
+    )]
+    output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct RecolourArgs {
+    #[arg(help = "A .mbdata file saved by render")]
+    data: PathBuf,
+    #[arg(
+        long,
+        default_value = "default",
+        value_parser = parse_palette,
+        help = "default, rainbow or green-black"
+    )]
+    palette: Colour,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "How far round the palette to start, from 0 to 1"
+    )]
+    palette_offset: f64,
+    #[arg(
+        long,
+        default_value = "escape-time",
+        value_parser = parse_colouring,
+        help = "escape-time, distance or distance-bands"
+    )]
+    colouring: Colouring,
+    #[arg(
+        short,
+        long,
+        default_value = "output.png",
+        help = "Where to save the image"
+    )]
+    output: PathBuf,
+}
+
+// How to render, whatever the view
+#[derive(Debug, Args)]
+struct ImageArgs {
+    #[arg(long, default_value_t = 1000)]
+    width: u64,
+    #[arg(long, default_value_t = 1000)]
+    height: u64,
+    #[arg(
+        long,
+        default_value = "default",
+        value_parser = parse_palette,
+        help = "default, rainbow or green-black"
+    )]
+    palette: Colour,
+    #[arg(
+        long,
+        default_value = "mandelbrot",
+        value_parser = parse_formula,
+        help = "mandelbrot, multibrot:<power>, burning-ship, tricorn, celtic, buffalo, \
+                or a formula of z and c like \"z^3 - z + c\""
+    )]
+    formula: Fractal,
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Average this many samples across and down each pixel"
+    )]
+    supersample: u32,
+    #[arg(
+        long,
+        default_value = "grid",
+        value_parser = parse_pattern,
+        help = "Where the samples go in each pixel: grid, jittered or rotated-grid"
+    )]
+    pattern: SamplePattern,
+    #[arg(long, help = "Only supersample pixels on edges")]
+    adaptive: bool,
+    #[arg(long, default_value_t = DEFAULT_BAILOUT, help = "Escape radius")]
+    bailout: f64,
+    #[arg(
+        long,
+        default_value = "escape-time",
+        value_parser = parse_colouring,
+        help = "escape-time, distance or distance-bands"
+    )]
+    colouring: Colouring,
+    #[arg(
+        long,
+        default_value = "black",
+        value_parser = parse_interior,
+        help = "black, period, multiplier or distance"
+    )]
+    interior: InteriorColouring,
+    #[arg(
+        long,
+        value_parser = parse_complex,
+        allow_hyphen_values = true,
+        help = "Render the julia set of this c, written as re,im"
+    )]
+    julia: Option<Complex<f64>>,
+    #[arg(long, help = "Use perturbation, for zooms past about 1e15")]
+    deep: bool,
+}
+
+impl ImageArgs {
+    fn params(&self) -> RenderParams<Fractal> {
+        RenderParams {
+            width: self.width,
+            height: self.height,
+            perturbation: self.deep,
+            julia: self.julia,
+            formula: self.formula.clone(),
+            interior_checks: InteriorChecks::ALL,
+            colouring: self.colouring,
+            interior_colouring: self.interior,
+            bailout: self.bailout,
+            supersampling: Supersampling {
+                size: self.supersample.max(1),
+                pattern: self.pattern,
+                adaptive: self.adaptive,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err("the image needs to be at least 1x1".to_string());
+        }
+        // the smooth iteration count takes logs of both of these, which are 0 at 1
+        if self.bailout.is_nan() || self.bailout <= 1.0 {
+            return Err("the bailout has to be above 1".to_string());
+        }
+        if let Fractal::RealMultibrot(power) = self.formula {
+            if power.is_nan() || power <= 1.0 {
+                return Err("the multibrot power has to be above 1".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RenderArgs {
+    fn validate(&self) -> Result<(), String> {
+        if self.zoom.is_negative() || self.zoom.normalised() == Decimal::zero(0) {
+            return Err("the zoom has to be above 0".to_string());
+        }
+        // the plane works in f64s, so a pixel has to be a size one can hold
+        let resolution = self.params().resolution.to_f64();
+        if !resolution.is_finite() || resolution <= 0.0 {
+            return Err("the zoom is too far out or in to render".to_string());
+        }
+        self.image.validate()
+    }
+
+    fn params(&self) -> RenderParams<Fractal> {
+        // enough digits for the size of a pixel, plus some spare
+        let scale = self.zoom.with_scale(0).to_string().len() as u32 + 25;
+        let resolution = Decimal::from_f64(CANVAS_SIZE as f64, 0).div(
+            &self
+                .zoom
+                .mul_exact(&Decimal::from_f64(self.image.width as f64, 0)),
+            scale,
+        );
+        RenderParams {
+            centre: ComplexDecimal::new(self.re.clone(), self.im.clone()),
+            resolution: resolution.normalised(),
+            max_iterations: self.iterations,
+            palette_offset: self.palette_offset,
+            projection: self.projection,
+            ..self.image.params()
+        }
+    }
+}
+
+// prints how far through pixels it is as a percentage on stderr, whenever that goes up
+fn report_progress(what: &str, pixels: u64) -> impl Fn(u64) + Sync + '_ {
+    let percent = AtomicU64::new(0);
+    move |pixels_done: u64| {
+        let now = pixels_done * 100 / pixels.max(1);
+        if percent.fetch_max(now, Ordering::Relaxed) < now {
+            eprint!("\r{} {}%", what, now);
+        }
+    }
+}
+
+// render an image from the command line, showing how far it's got on stderr.
+// Big PNGs that get interrupted carry on where they left off when run again
+pub fn render(args: RenderArgs) -> Result<(), String> {
+    args.validate()?;
+    if let Some(frames) = args.cycle {
+        return cycle_palette(&args, frames);
+    }
+    if is_data_path(&args.output) {
+        let params = args.params();
+        let saved = save_data(
+            &params,
+            &args.output,
+            &AtomicBool::new(false),
+            report_progress("Rendering", params.width * params.height),
+        );
+        eprintln!();
+        return saved;
+    }
+    let format = ImageFormat::from_path(&args.output)
+        .map_err(|error| format!("couldn't save {}: {}", args.output.display(), error))?;
+    let params = args.params();
+    let saved = export_image(
+        &params,
+        args.image.palette.to_array(),
+        &args.output,
+        format,
+        &AtomicBool::new(false),
+        report_progress("Rendering", params.width * params.height),
+    );
+    eprintln!();
+    saved
+}
+
+// render the view once for every frame of a loop round the palette, into a GIF or animated PNG
+fn cycle_palette(args: &RenderArgs, frames: u64) -> Result<(), String> {
+    if frames == 0 {
+        return Err("a palette cycle needs at least one frame".to_string());
+    }
+    let params = args.params();
+    let view = Keyframe {
+        frame: 0,
+        centre: params.centre.clone(),
+        zoom: args.zoom.to_f64(),
+        max_iterations: params.max_iterations,
+        palette_offset: params.palette_offset,
+    };
+    let saved = save_animated(
+        &Animation::palette_cycle(view, frames),
+        &params,
+        args.image.palette.to_array(),
+        &args.output,
+        args.playback.playback(),
+        &AtomicBool::new(false),
+        report_progress("Rendering", frames * params.width * params.height),
+    );
+    eprintln!();
+    saved
+}
+
+// render every frame of an animation from a file of keyframes into a folder of numbered PNGs,
+// or into one GIF or animated PNG
+pub fn animate(args: AnimateArgs) -> Result<(), String> {
+    args.image.validate()?;
+    let animation: Animation = fs::read_to_string(&args.keyframes)
+        .map_err(|error| format!("couldn't read {}: {}", args.keyframes.display(), error))?
+        .parse()
+        .map_err(|error| format!("couldn't read {}: {}", args.keyframes.display(), error))?;
+    if animation.frames() == 0 {
+        return Err(format!(
+            "there are no keyframes in {}",
+            args.keyframes.display()
+        ));
+    }
+    let base = args.image.params();
+    let rendered = export_animation(
+        &animation,
+        &base,
+        args.image.palette.to_array(),
+        &args.output,
+        args.playback.playback(),
+        &AtomicBool::new(false),
+        report_progress(
+            &format!("Rendering {} frames", animation.frames()),
+            animation.frames() * base.width * base.height,
+        ),
+    );
+    eprintln!();
+    rendered
+}
+
+// turn an exponential map into the frames of a zoom, without rendering anything
+pub fn zoom_frames(args: ZoomFramesArgs) -> Result<(), String> {
+    if args.width == 0 || args.height == 0 || args.frames == 0 {
+        return Err("the frames need to be at least 1x1, and there has to be one".to_string());
+    }
+    let map = image::open(&args.map)
+        .map_err(|error| format!("couldn't read {}: {}", args.map.display(), error))?
+        .into_rgb8();
+    let done = render_zoom_frames(
+        &map,
+        args.width,
+        args.height,
+        args.frames,
+        &args.output,
+        report_progress("Saving frames", args.frames),
+    );
+    eprintln!();
+    done
+}
+
+// colour the iteration data saved from a view, which doesn't iterate anything
+pub fn recolour(args: RecolourArgs) -> Result<(), String> {
+    let format = ImageFormat::from_path(&args.output)
+        .map_err(|error| format!("couldn't save {}: {}", args.output.display(), error))?;
+    iteration_data::recolour(
+        &args.data,
+        args.palette.to_array(),
+        args.colouring,
+        args.palette_offset,
+    )?
+    .into_image()
+    .save_with_format(&args.output, format)
+    .map_err(|error| format!("couldn't save {}: {}", args.output.display(), error))
+}
+
+fn parse_palette(name: &str) -> Result<Colour, String> {
+    match name {
+        "default" => Ok(Colour::Default),
+        "rainbow" => Ok(Colour::Rainbow),
+        "green-black" => Ok(Colour::GreenBlack),
+        _ => Err(format!("there's no palette called {}", name)),
+    }
+}
+
+fn parse_formula(formula: &str) -> Result<Fractal, String> {
+    Ok(match formula {
+        "mandelbrot" => Fractal::Mandelbrot,
+        "burning-ship" => Fractal::BurningShip,
+        "tricorn" => Fractal::Tricorn,
+        "celtic" => Fractal::Celtic,
+        "buffalo" => Fractal::Buffalo,
+        _ => match formula.strip_prefix("multibrot:") {
+            Some(power) => {
+                let power: f64 = power
+                    .parse()
+                    .map_err(|_| format!("{} isn't a power", power))?;
+                if power.fract() == 0.0 && power >= 2.0 {
+                    Fractal::Multibrot(power as u32)
+                } else {
+                    Fractal::RealMultibrot(power)
+                }
+            }
+            None => Fractal::Custom(Arc::new(
+                Expression::parse(formula).map_err(|error| error.to_string())?,
+            )),
+        },
+    })
+}
+
+fn parse_pattern(name: &str) -> Result<SamplePattern, String> {
+    match name {
+        "grid" => Ok(SamplePattern::Grid),
+        "jittered" => Ok(SamplePattern::Jittered),
+        "rotated-grid" => Ok(SamplePattern::RotatedGrid),
+        _ => Err(format!("there's no sample pattern called {}", name)),
+    }
+}
+
+fn parse_projection(name: &str) -> Result<Projection, String> {
+    match name {
+        "flat" => Ok(Projection::Flat),
+        "exponential" => Ok(Projection::Exponential),
+        _ => Err(format!("there's no projection called {}", name)),
+    }
+}
+
+fn parse_colouring(name: &str) -> Result<Colouring, String> {
+    match name {
+        "escape-time" => Ok(Colouring::EscapeTime),
+        "distance" => Ok(Colouring::Distance),
+        "distance-bands" => Ok(Colouring::DistanceBands),
+        _ => Err(format!("there's no colouring called {}", name)),
+    }
+}
+
+fn parse_interior(name: &str) -> Result<InteriorColouring, String> {
+    match name {
+        "black" => Ok(InteriorColouring::Black),
+        "period" => Ok(InteriorColouring::Period),
+        "multiplier" => Ok(InteriorColouring::Multiplier),
+        "distance" => Ok(InteriorColouring::Distance),
+        _ => Err(format!("there's no interior colouring called {}", name)),
+    }
+}
+
+fn parse_complex(number: &str) -> Result<Complex<f64>, String> {
+    let invalid = || format!("{} isn't a complex number like -0.8,0.156", number);
+    let (re, im) = number.split_once(',').ok_or_else(invalid)?;
+    Ok(Complex::new(
+        re.trim().parse().map_err(|_| invalid())?,
+        im.trim().parse().map_err(|_| invalid())?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(args: &str) -> Result<(), String> {
+        let cli = Cli::try_parse_from(format!("mandelbrot_explorer render {}", args).split(' '))
+            .map_err(|error| error.to_string())?;
+        match cli.command {
+            Some(CliCommand::Render(args)) => args.validate(),
+            _ => unreachable!("not a render"),
+        }
+    }
+
+    #[test]
+    fn rejects_views_that_cant_render() {
+        assert_eq!(validate("--zoom 125"), Ok(()));
+        assert_eq!(validate("--zoom 1e-300"), Ok(()));
+        assert_eq!(validate("--formula multibrot:1.5 --bailout 1.01"), Ok(()));
+        let zoom = Err("the zoom has to be above 0".to_string());
+        assert_eq!(validate("--zoom 0"), zoom);
+        assert_eq!(validate("--zoom 0.000e5"), zoom);
+        assert_eq!(validate("--zoom=-125"), zoom);
+        let range = Err("the zoom is too far out or in to render".to_string());
+        assert_eq!(validate("--zoom 1e-400"), range);
+        assert_eq!(validate("--zoom 1e400"), range);
+        let bailout = Err("the bailout has to be above 1".to_string());
+        assert_eq!(validate("--bailout 1"), bailout);
+        assert_eq!(validate("--bailout=-4"), bailout);
+        assert_eq!(validate("--bailout NaN"), bailout);
+        let power = Err("the multibrot power has to be above 1".to_string());
+        assert_eq!(validate("--formula multibrot:1"), power);
+        assert_eq!(validate("--formula multibrot:0.5"), power);
+        assert_eq!(validate("--formula multibrot:-3"), power);
+        assert_eq!(validate("--formula multibrot:NaN"), power);
+    }
+}
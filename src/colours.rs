@@ -1,3 +1,97 @@
+use crate::backend::Interior;
+
+// the palette colour for a smooth iteration count, spaced out further the more iterations
+// it took so the bands don't get too thin close to the set. Starting 10 iterations in skips
+// the quickly changing start of the square root, like colouring always has. The offset
+// shifts every colour along the palette, by a whole palette for 1
+pub fn palette_colour(colours: &[(u8, u8, u8)], continuous: f64, offset: f64) -> (u8, u8, u8) {
+    let shift = (offset.rem_euclid(1.0) * colours.len() as f64).round() as usize;
+    let position = ((continuous + 10.0).max(0.0).sqrt() * 256.0).round() as usize;
+    colours[(position + shift) % colours.len()]
+}
+
+// how bright a point should be given how many pixels away from the set it is,
+// anything more than a couple of pixels away is fully lit which leaves thin dark filaments
+pub fn distance_shade(pixels: f64) -> f64 {
+    (pixels / 2.0).clamp(0.0, 1.0).sqrt()
+}
+
+// How points inside the set get coloured, which is just black unless the backend
+// is asked to work out what cycle each point settles into
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum InteriorColouring {
+    #[default]
+    Black,
+    // a different hue for each period of attracting cycle, so every bulb stands out
+    Period,
+    // hue from the angle of the multiplier and brightness from its size,
+    // which shows the "spokes" inside each bulb
+    Multiplier,
+    // shaded by how far the point is from the edge of the set, like the exterior distance modes
+    Distance,
+}
+
+impl InteriorColouring {
+    pub const ALL: [InteriorColouring; 4] = [
+        InteriorColouring::Black,
+        InteriorColouring::Period,
+        InteriorColouring::Multiplier,
+        InteriorColouring::Distance,
+    ];
+
+    // colour of a point that never escapes, the distance being in pixels
+    pub fn colour(self, interior: &Interior, pixels: Option<f64>) -> (u8, u8, u8) {
+        match self {
+            InteriorColouring::Black => (0, 0, 0),
+            // stepping round the colour wheel by the golden angle keeps nearby periods apart
+            InteriorColouring::Period => hsv(interior.period as f64 * 0.618034, 0.7, 0.9),
+            InteriorColouring::Multiplier => hsv(
+                interior.multiplier.arg() / std::f64::consts::TAU,
+                0.8,
+                interior.multiplier.norm().clamp(0.0, 1.0),
+            ),
+            // fades in over a few dozen pixels rather than the couple that exterior shading uses,
+            // as the inside of a bulb is a lot bigger than a filament
+            InteriorColouring::Distance => {
+                let shade = ((pixels.unwrap_or(0.0) / 32.0).tanh() * 255.0).round() as u8;
+                (shade, shade, shade)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for InteriorColouring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                InteriorColouring::Black => "Black inside",
+                InteriorColouring::Period => "Period inside",
+                InteriorColouring::Multiplier => "Multiplier inside",
+                InteriorColouring::Distance => "Distance inside",
+            }
+        )
+    }
+}
+
+// hue wraps around every 1.0, saturation and value go from 0 to 1
+fn hsv(hue: f64, saturation: f64, value: f64) -> (u8, u8, u8) {
+    let hue = hue.rem_euclid(1.0) * 6.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let channel = |c: f64| ((c + value - chroma) * 255.0).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
 // precomputed arrays for various gradients
 
 pub const DEFAULT_COLOURS: [(u8, u8, u8); 767] = [
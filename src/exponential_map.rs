@@ -0,0 +1,207 @@
+use crate::animation::frame_path;
+use crate::backend::Projection;
+use crate::renderer::PixelBuffer;
+use crate::supersampling::blend;
+use image::{ImageFormat, RgbImage};
+use num::Complex;
+use rayon::prelude::*;
+use std::fs;
+use std::path::Path;
+
+// Frames of a zoom into the centre of an exponential map (see Projection::Exponential), read
+// straight out of the map instead of being rendered. Zooms are given compared to the square the
+// top row of the map goes round, so a frame at zoom 1 is as wide as that square and one at zoom
+// 10 is a tenth as wide
+
+// How far frames this many pixels wide can zoom in before the middle of them needs rings smaller
+// than the ones at the bottom of the map
+pub fn deepest_zoom(map: &RgbImage, frame_width: u64) -> f64 {
+    let smallest_ring =
+        Projection::exponential_offset(0.0, map.height() as f64 - 1.0, map.width() as u64);
+    1.0 / (smallest_ring.norm() * frame_width as f64)
+}
+
+// A frame of the zoom. Each pixel is blended from the four pixels of the map around it in linear
+// light, with the columns wrapping round. Anything closer to the centre than the bottom row of
+// the map gets the bottom row, which only matters for the middle pixel of frames past deepest_zoom
+pub fn frame(map: &RgbImage, zoom: f64, width: u64, height: u64) -> PixelBuffer {
+    let (map_width, map_height) = (map.width() as u64, map.height());
+    let turn = std::f64::consts::TAU / map_width as f64;
+    let mut frame = PixelBuffer::new(width as u32, height as u32);
+    let rows: Vec<Vec<(u8, u8, u8)>> = (0..height)
+        .into_par_iter()
+        .map(|y| {
+            (0..width)
+                .map(|x| {
+                    // where the pixel is compared to the centre, lined up the same way as a
+                    // flat plane's pixels, then back into a row and column of the map
+                    let offset = Complex::new(
+                        x as f64 - width as f64 / 2.0,
+                        y as f64 - height as f64 / 2.0,
+                    ) / (zoom * width as f64);
+                    let row = (-(offset.norm() * std::f64::consts::SQRT_2).ln() / turn)
+                        .clamp(0.0, (map_height - 1) as f64);
+                    let column = offset.arg().rem_euclid(std::f64::consts::TAU) / turn;
+                    let (top, left) = (row.floor(), column.floor());
+                    let (down, along) = (row - top, column - left);
+                    let top = top as u32;
+                    let bottom = (top + 1).min(map_height - 1);
+                    let left = left as u64 % map_width;
+                    let right = (left + 1) % map_width;
+                    let pixel = |column: u64, row: u32| {
+                        let [r, g, b] = map.get_pixel(column as u32, row).0;
+                        (r, g, b)
+                    };
+                    blend([
+                        (pixel(left, top), (1.0 - along) * (1.0 - down)),
+                        (pixel(right, top), along * (1.0 - down)),
+                        (pixel(left, bottom), (1.0 - along) * down),
+                        (pixel(right, bottom), along * down),
+                    ])
+                })
+                .collect()
+        })
+        .collect();
+    for (y, row) in rows.into_iter().enumerate() {
+        for (x, colour) in row.into_iter().enumerate() {
+            frame.set(x as u32, y as u32, colour);
+        }
+    }
+    frame
+}
+
+// Save a zoom from the whole square of the map all the way in to deepest_zoom into a folder as
+// numbered PNGs, the same as an animation's frames, with the zoom going up by the same factor
+// every frame. Progress is called with how many frames are done
+pub fn render_zoom_frames(
+    map: &RgbImage,
+    width: u64,
+    height: u64,
+    frames: u64,
+    folder: &Path,
+    progress: impl Fn(u64),
+) -> Result<(), String> {
+    let deepest = deepest_zoom(map, width);
+    if deepest < 1.0 {
+        return Err(format!(
+            "the map doesn't go deep enough for frames {} pixels wide, it needs to be taller",
+            width
+        ));
+    }
+    fs::create_dir_all(folder)
+        .map_err(|error| format!("couldn't write {}: {}", folder.display(), error))?;
+    for index in 0..frames {
+        let along = index as f64 / (frames - 1).max(1) as f64;
+        let path = frame_path(folder, index);
+        frame(map, deepest.powf(along), width, height)
+            .into_image()
+            .save_with_format(&path, ImageFormat::Png)
+            .map_err(|error| format!("couldn't save {}: {}", path.display(), error))?;
+        progress(index + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colours::DEFAULT_COLOURS;
+    use crate::precision::{ComplexDecimal, Decimal};
+    use crate::renderer::{render_from_params_parallel, RenderParams};
+
+    // At the deepest zoom the pixels right next to the middle of the frame come from the bottom
+    // row of the map and nothing above it, however tall the map is
+    #[test]
+    fn deepest_zoom_goes_down_to_the_bottom_row() {
+        for (width, height, frame_width) in [(360, 100, 64), (720, 400, 640), (100, 1000, 4)] {
+            let mut map = RgbImage::new(width, height);
+            for column in 0..width {
+                map.put_pixel(column, height - 1, image::Rgb([255, 0, 0]));
+            }
+            let zoom = deepest_zoom(&map, frame_width);
+            let next_to_middle = |zoom: f64| {
+                frame(&map, zoom, frame_width, frame_width)
+                    .get(frame_width as u32 / 2 + 1, frame_width as u32 / 2)
+            };
+            assert_eq!(next_to_middle(zoom), (255, 0, 0), "a map {} tall", height);
+            assert_ne!(
+                next_to_middle(zoom * 0.99),
+                (255, 0, 0),
+                "a map {} tall",
+                height
+            );
+        }
+    }
+
+    // a view near the top of the set with some of its edge in, rendered flat or as a map
+    fn view(resolution: f64, width: u64, height: u64, projection: Projection) -> RenderParams {
+        RenderParams {
+            centre: ComplexDecimal::new(Decimal::from_f64(-0.16, 0), Decimal::from_f64(1.04, 0)),
+            resolution: Decimal::from_f64(resolution, 0),
+            max_iterations: 500,
+            width,
+            height,
+            projection,
+            ..Default::default()
+        }
+    }
+
+    // The average of each channel over 16x16 blocks of an image, which smooths over the
+    // aliasing of single samples that lands differently in the map and in a flat render
+    fn blocks(image: &PixelBuffer) -> Vec<f64> {
+        let columns = image.width().div_ceil(16);
+        let mut averages = vec![0.0; (columns * image.height().div_ceil(16) * 3) as usize];
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let block = ((y / 16 * columns + x / 16) * 3) as usize;
+                let (r, g, b) = image.get(x, y);
+                for (average, channel) in averages[block..block + 3].iter_mut().zip([r, g, b]) {
+                    *average += channel as f64 / 256.0;
+                }
+            }
+        }
+        averages
+    }
+
+    // A frame read out of the map looks like the same view rendered directly. With the frame
+    // zoomed in by another 5% it's a lot further off, so this would notice the zoom being wrong
+    #[test]
+    fn frames_match_rendering_the_view() {
+        let (map_width, frame_width) = (1440, 160);
+        let map = render_from_params_parallel(
+            &view(
+                0.02 / map_width as f64,
+                map_width,
+                1200,
+                Projection::Exponential,
+            ),
+            &DEFAULT_COLOURS,
+        )
+        .into_image();
+        for zoom in [1.0, 4.0, 16.0] {
+            let flat = view(
+                0.02 / zoom / frame_width as f64,
+                frame_width,
+                frame_width,
+                Projection::Flat,
+            );
+            let rendered = blocks(&render_from_params_parallel(&flat, &DEFAULT_COLOURS));
+            let difference = |zoom: f64| {
+                let read = blocks(&frame(&map, zoom, frame_width, frame_width));
+                read.iter()
+                    .zip(&rendered)
+                    .map(|(a, b)| (a - b).abs())
+                    .sum::<f64>()
+                    / read.len() as f64
+            };
+            let (right, wrong) = (difference(zoom), difference(zoom * 1.05));
+            assert!(
+                right < 3.0 && wrong > 3.0 * right,
+                "{} apart at zoom {}, {} further in",
+                right,
+                zoom,
+                wrong
+            );
+        }
+    }
+}
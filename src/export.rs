@@ -0,0 +1,166 @@
+use crate::backend::{Formula, MandelbrotPlane};
+use crate::renderer::{
+    plane_from_params, render_plane_guessing, render_plane_in_passes, PixelBuffer, RenderParams,
+};
+use crate::supersampling::supersample;
+use image::ImageFormat;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+// how many rows of a png get rendered at once, which is about 20MB for a 50,000 pixel wide image
+pub const STRIP_HEIGHT: u64 = 128;
+
+// Render a view to a file. PNGs are rendered a strip of rows at a time and each strip gets saved
+// into a folder next to the file as soon as it's done, then they're all streamed into the file
+// one after another, so only about one strip is ever in memory however big the image is. If that
+// gets interrupted, exporting the same view to the same file again carries on from the strips
+// that are already there. Other formats are rendered in one go. Progress is called with how many
+// pixels are done, and cancelling leaves whatever strips were finished for next time
+pub fn export_image<F: Formula + Clone>(
+    params: &RenderParams<F>,
+    colours: &[(u8, u8, u8)],
+    path: &Path,
+    format: ImageFormat,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Result<(), String> {
+    let plane = plane_from_params(params);
+    if format == ImageFormat::Png {
+        return export_png_in_strips(params, &plane, colours, path, cancelled, progress);
+    }
+    render(params, &plane, colours, cancelled, progress)
+        .ok_or("the export was cancelled")?
+        .into_image()
+        .save_with_format(path, format)
+        .map_err(|error| format!("couldn't save {}: {}", path.display(), error))
+}
+
+// the folder the strips of a png export go in while it's being rendered
+pub fn strips_folder(path: &Path) -> PathBuf {
+    let mut folder = path.as_os_str().to_owned();
+    folder.push(".strips");
+    PathBuf::from(folder)
+}
+
+fn export_png_in_strips<F: Formula + Clone>(
+    params: &RenderParams<F>,
+    plane: &MandelbrotPlane<F>,
+    colours: &[(u8, u8, u8)],
+    path: &Path,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Result<(), String> {
+    let folder = strips_folder(path);
+    let io_error = |path: &Path| {
+        let path = path.display().to_string();
+        move |error: std::io::Error| format!("couldn't write {}: {}", path, error)
+    };
+    // Everything the strips depend on, so strips left over from exporting some other view to
+    // the same file don't get used. The size of the strips goes in too in case it ever changes
+    let view = format!("{:?}\n{:?}\n{}\n", params, colours, STRIP_HEIGHT);
+    let view_path = folder.join("view.txt");
+    if fs::read_to_string(&view_path).ok().as_deref() != Some(view.as_str()) {
+        if folder.exists() {
+            fs::remove_dir_all(&folder).map_err(io_error(&folder))?;
+        }
+        fs::create_dir_all(&folder).map_err(io_error(&folder))?;
+        fs::write(&view_path, &view).map_err(io_error(&view_path))?;
+    }
+
+    let (width, height) = (params.width, params.height);
+    let strips = height.div_ceil(STRIP_HEIGHT);
+    let strip_path = |strip: u64| folder.join(format!("strip{:06}.png", strip));
+    for strip in 0..strips {
+        let top = strip * STRIP_HEIGHT;
+        let rows = STRIP_HEIGHT.min(height - top);
+        let pixels_before = top * width;
+        if strip_path(strip).exists() {
+            progress(pixels_before + rows * width);
+            continue;
+        }
+        // adaptive supersampling compares pixels with the ones above and below, so the strip
+        // gets a row of the strips either side of it too, to pick the same pixels as in one go
+        let margin = if params.supersampling.adaptive { 1 } else { 0 };
+        let above = margin.min(top);
+        let below = margin.min(height - top - rows);
+        let image = render(
+            params,
+            &plane.rows(top - above, above + rows + below),
+            colours,
+            cancelled,
+            |pixels_done| progress(pixels_before + pixels_done.min(rows * width)),
+        )
+        .ok_or("the export was cancelled")?
+        .into_image();
+        // saved under another name first, so any strip that's there is a whole one
+        let unfinished = folder.join("strip.png.part");
+        image::imageops::crop_imm(&image, 0, above as u32, width as u32, rows as u32)
+            .to_image()
+            .save_with_format(&unfinished, ImageFormat::Png)
+            .map_err(|error| format!("couldn't save {}: {}", unfinished.display(), error))?;
+        fs::rename(&unfinished, strip_path(strip)).map_err(io_error(&unfinished))?;
+    }
+
+    let file = File::create(path).map_err(io_error(path))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let png_error =
+        |error: png::EncodingError| format!("couldn't save {}: {}", path.display(), error);
+    let mut writer = encoder
+        .write_header()
+        .map_err(png_error)?
+        .into_stream_writer()
+        .map_err(png_error)?;
+    for strip in 0..strips {
+        let strip_path = strip_path(strip);
+        let image = image::open(&strip_path)
+            .map_err(|error| format!("couldn't read {}: {}", strip_path.display(), error))?
+            .into_rgb8();
+        writer.write_all(image.as_raw()).map_err(io_error(path))?;
+    }
+    writer.finish().map_err(png_error)?;
+    fs::remove_dir_all(&folder).map_err(io_error(&folder))
+}
+
+// render a view in one go, the same way as exporting it to anything but a png
+pub fn render_image<F: Formula + Clone>(
+    params: &RenderParams<F>,
+    colours: &[(u8, u8, u8)],
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Option<PixelBuffer> {
+    render(
+        params,
+        &plane_from_params(params),
+        colours,
+        cancelled,
+        progress,
+    )
+}
+
+// render a plane in one go the same way the explorer would, without any tiles
+fn render<F: Formula>(
+    params: &RenderParams<F>,
+    plane: &MandelbrotPlane<F>,
+    colours: &[(u8, u8, u8)],
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Option<PixelBuffer> {
+    let base_progress = |pixels_done| progress(params.supersampling.base_progress(pixels_done));
+    let image = if params.guessing {
+        render_plane_guessing(plane, colours, cancelled, base_progress)
+    } else {
+        render_plane_in_passes(plane, colours, 1, cancelled, base_progress, |_| {})
+    }?;
+    supersample(
+        plane,
+        colours,
+        params.supersampling,
+        image,
+        cancelled,
+        progress,
+    )
+}
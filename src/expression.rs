@@ -0,0 +1,563 @@
+use crate::backend::Formula;
+use num::Complex;
+use std::fmt::Formatter;
+
+// deepest the evaluation stack is allowed to get, so evaluating never needs to allocate
+const MAX_STACK: usize = 32;
+
+// how deeply brackets, minus signs and powers can nest, so parsing can't run out of stack
+const MAX_NESTING: usize = 200;
+
+// A formula typed in by the user, like "z^3 - z + c" or "sin(z)/c + z^2",
+// compiled down to a list of stack operations that can be evaluated quickly
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    source: String,
+    ops: Vec<Op>,
+    // the power of z the formula grows like, see degree()
+    degree: f64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Op {
+    Z,
+    C,
+    Constant(Complex<f64>),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Neg,
+    // whole number powers are much faster (and more accurate) than general ones
+    PowI(i32),
+    Pow,
+    Function(Function),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Sinh,
+    Cosh,
+    Tanh,
+    Exp,
+    Log,
+    Sqrt,
+    Abs,
+    Conj,
+    Re,
+    Im,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "sinh" => Function::Sinh,
+            "cosh" => Function::Cosh,
+            "tanh" => Function::Tanh,
+            "exp" => Function::Exp,
+            "log" | "ln" => Function::Log,
+            "sqrt" => Function::Sqrt,
+            "abs" => Function::Abs,
+            "conj" => Function::Conj,
+            "re" => Function::Re,
+            "im" => Function::Im,
+            _ => return None,
+        })
+    }
+
+    fn apply(self, x: Complex<f64>) -> Complex<f64> {
+        match self {
+            Function::Sin => x.sin(),
+            Function::Cos => x.cos(),
+            Function::Tan => x.tan(),
+            Function::Sinh => x.sinh(),
+            Function::Cosh => x.cosh(),
+            Function::Tanh => x.tanh(),
+            Function::Exp => x.exp(),
+            Function::Log => x.ln(),
+            Function::Sqrt => x.sqrt(),
+            Function::Abs => Complex::new(x.norm(), 0.0),
+            Function::Conj => x.conj(),
+            Function::Re => Complex::new(x.re, 0.0),
+            Function::Im => Complex::new(x.im, 0.0),
+        }
+    }
+}
+
+// What went wrong parsing an expression, and the column (counting from 1) it went wrong at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub column: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+    End,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "'{}'", number),
+            Token::Name(name) => write!(f, "'{}'", name),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+            Token::End => write!(f, "end of formula"),
+        }
+    }
+}
+
+// split the source into tokens, along with the column each one starts at
+fn tokenise(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let character = chars[i];
+        if character.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = if character.is_ascii_digit() || character == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1
+            }
+            let text: String = chars[start..i].iter().collect();
+            Token::Number(text.parse().map_err(|_| ParseError {
+                message: format!("invalid number '{}'", text),
+                column: start + 1,
+            })?)
+        } else if character.is_alphabetic() {
+            while i < chars.len() && chars[i].is_alphanumeric() {
+                i += 1
+            }
+            Token::Name(chars[start..i].iter().collect::<String>().to_lowercase())
+        } else if "+-*/^()".contains(character) {
+            i += 1;
+            Token::Symbol(character)
+        } else {
+            return Err(ParseError {
+                message: format!("unexpected character '{}'", character),
+                column: start + 1,
+            });
+        };
+        tokens.push((token, start + 1));
+    }
+    tokens.push((Token::End, chars.len() + 1));
+    Ok(tokens)
+}
+
+// Recursive descent parser, from lowest to highest precedence:
+// sum := product (('+' | '-') product)*
+// product := unary (('*' | '/')? unary)*   (no operator means multiply, so "2z" works)
+// unary := '-' unary | power
+// power := primary ('^' unary)?
+// primary := number | name | function '(' sum ')' | '(' sum ')'
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    ops: Vec<Op>,
+    // how many calls to unary deep the parser is, which every kind of nesting goes through
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn column(&self) -> usize {
+        self.tokens[self.position].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].0.clone();
+        if token != Token::End {
+            self.position += 1
+        }
+        token
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, ParseError> {
+        Err(ParseError {
+            message,
+            column: self.column(),
+        })
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ParseError> {
+        if *self.peek() == Token::Symbol(symbol) {
+            self.next();
+            Ok(())
+        } else {
+            self.error(format!("expected '{}' but found {}", symbol, self.peek()))
+        }
+    }
+
+    // add an op, working out anything that only involves constants straight away
+    fn emit(&mut self, op: Op) {
+        let constant = |op: Option<&Op>| match op {
+            Some(Op::Constant(value)) => Some(*value),
+            _ => None,
+        };
+        let length = self.ops.len();
+        let folded = match op {
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow if length >= 2 => {
+                match (
+                    constant(self.ops.get(length - 2)),
+                    constant(self.ops.get(length - 1)),
+                ) {
+                    (Some(a), Some(b)) => {
+                        self.ops.truncate(length - 2);
+                        Some(apply_binary(op, a, b))
+                    }
+                    _ => None,
+                }
+            }
+            Op::Neg | Op::PowI(_) | Op::Function(_) => constant(self.ops.last()).map(|a| {
+                self.ops.pop();
+                apply_unary(op, a)
+            }),
+            _ => None,
+        };
+        self.ops.push(folded.map_or(op, Op::Constant))
+    }
+
+    fn sum(&mut self) -> Result<(), ParseError> {
+        self.product()?;
+        loop {
+            match self.peek() {
+                Token::Symbol('+') => {
+                    self.next();
+                    self.product()?;
+                    self.emit(Op::Add)
+                }
+                Token::Symbol('-') => {
+                    self.next();
+                    self.product()?;
+                    self.emit(Op::Sub)
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<(), ParseError> {
+        self.unary()?;
+        loop {
+            match self.peek() {
+                Token::Symbol('*') => {
+                    self.next();
+                    self.unary()?;
+                    self.emit(Op::Mul)
+                }
+                Token::Symbol('/') => {
+                    self.next();
+                    self.unary()?;
+                    self.emit(Op::Div)
+                }
+                Token::Number(_) | Token::Name(_) | Token::Symbol('(') => {
+                    self.unary()?;
+                    self.emit(Op::Mul)
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<(), ParseError> {
+        if self.depth == MAX_NESTING {
+            return self.error("the formula is too deeply nested".to_string());
+        }
+        self.depth += 1;
+        let parsed = if *self.peek() == Token::Symbol('-') {
+            self.next();
+            self.unary().map(|()| self.emit(Op::Neg))
+        } else {
+            self.power()
+        };
+        self.depth -= 1;
+        parsed
+    }
+
+    fn power(&mut self) -> Result<(), ParseError> {
+        self.primary()?;
+        if *self.peek() != Token::Symbol('^') {
+            return Ok(());
+        }
+        self.next();
+        // whole number exponents get their own op, unless they are raised to a power themselves
+        if let Token::Number(exponent) = *self.peek() {
+            let after = &self.tokens[self.position + 1].0;
+            if exponent.fract() == 0.0
+                && exponent <= i32::MAX as f64
+                && *after != Token::Symbol('^')
+            {
+                self.next();
+                self.emit(Op::PowI(exponent as i32));
+                return Ok(());
+            }
+        }
+        self.unary()?;
+        self.emit(Op::Pow);
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<(), ParseError> {
+        let column = self.column();
+        match self.next() {
+            Token::Number(number) => self.emit(Op::Constant(Complex::new(number, 0.0))),
+            Token::Name(name) => match name.as_str() {
+                "z" => self.emit(Op::Z),
+                "c" => self.emit(Op::C),
+                "i" => self.emit(Op::Constant(Complex::new(0.0, 1.0))),
+                "pi" => self.emit(Op::Constant(Complex::new(std::f64::consts::PI, 0.0))),
+                "e" => self.emit(Op::Constant(Complex::new(std::f64::consts::E, 0.0))),
+                _ => {
+                    let Some(function) = Function::from_name(&name) else {
+                        return Err(ParseError {
+                            message: format!("unknown name '{}'", name),
+                            column,
+                        });
+                    };
+                    self.expect('(')?;
+                    self.sum()?;
+                    self.expect(')')?;
+                    self.emit(Op::Function(function))
+                }
+            },
+            Token::Symbol('(') => {
+                self.sum()?;
+                self.expect(')')?
+            }
+            token => {
+                return Err(ParseError {
+                    message: format!("expected a number, variable or '(' but found {}", token),
+                    column,
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+fn apply_binary(op: Op, a: Complex<f64>, b: Complex<f64>) -> Complex<f64> {
+    match op {
+        Op::Add => a + b,
+        Op::Sub => a - b,
+        Op::Mul => a * b,
+        Op::Div => a / b,
+        Op::Pow => a.powc(b),
+        _ => unreachable!("not a binary op"),
+    }
+}
+
+fn apply_unary(op: Op, a: Complex<f64>) -> Complex<f64> {
+    match op {
+        Op::Neg => -a,
+        Op::PowI(exponent) => a.powi(exponent),
+        Op::Function(function) => function.apply(a),
+        _ => unreachable!("not a unary op"),
+    }
+}
+
+// The power of z a list of ops grows like once z gets big, going by the highest power in each
+// sum as it's written, so "z^3 - z + c" is 3 and "z^2/(z + 1)" is 1. None if it doesn't grow like
+// a power of z at all, like "sin(z) + c". Along with each degree goes the value of anything that
+// works out to a constant, so "z^c" can be told apart from "z^2.5"
+fn degree(ops: &[Op]) -> Option<f64> {
+    let mut stack: Vec<(Option<f64>, Option<Complex<f64>>)> = Vec::new();
+    for op in ops {
+        let value = match *op {
+            Op::Z => (Some(1.0), None),
+            Op::C => (Some(0.0), None),
+            Op::Constant(value) => (Some(0.0), Some(value)),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => {
+                let (b, exponent) = stack.pop()?;
+                let (a, _) = stack.pop()?;
+                let degree = match (*op, a, b) {
+                    (Op::Add | Op::Sub, Some(a), Some(b)) => Some(a.max(b)),
+                    (Op::Mul, Some(a), Some(b)) => Some(a + b),
+                    (Op::Div, Some(a), Some(b)) => Some(a - b),
+                    (Op::Pow, Some(a), _) => match exponent {
+                        Some(exponent) if exponent.im == 0.0 => Some(a * exponent.re),
+                        _ if a == 0.0 && b == Some(0.0) => Some(0.0),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                (degree, None)
+            }
+            Op::Neg | Op::PowI(_) | Op::Function(_) => {
+                let (a, _) = stack.pop()?;
+                let degree = match *op {
+                    Op::PowI(exponent) => a.map(|a| a * exponent as f64),
+                    Op::Function(Function::Sqrt) => a.map(|a| a / 2.0),
+                    Op::Neg
+                    | Op::Function(Function::Abs | Function::Conj | Function::Re | Function::Im) => {
+                        a
+                    }
+                    // anything else of something that doesn't depend on z doesn't either
+                    _ => a.filter(|&a| a == 0.0),
+                };
+                (degree, None)
+            }
+        };
+        stack.push(value);
+    }
+    stack.pop()?.0
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenise(source)?,
+            position: 0,
+            ops: Vec::new(),
+            depth: 0,
+        };
+        if *parser.peek() == Token::End {
+            return parser.error("the formula is empty".to_string());
+        }
+        parser.sum()?;
+        if *parser.peek() != Token::End {
+            return parser.error(format!("unexpected {}", parser.peek()));
+        }
+
+        // check the stack never gets deeper than evaluate can handle
+        let mut depth: usize = 0;
+        for op in &parser.ops {
+            match op {
+                Op::Z | Op::C | Op::Constant(_) => depth += 1,
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => depth -= 1,
+                Op::Neg | Op::PowI(_) | Op::Function(_) => {}
+            }
+            if depth > MAX_STACK {
+                return Err(ParseError {
+                    message: "the formula is too deeply nested".to_string(),
+                    column: 1,
+                });
+            }
+        }
+
+        Ok(Expression {
+            source: source.trim().to_string(),
+            degree: degree(&parser.ops)
+                .filter(|&degree| degree > 1.0)
+                .unwrap_or(2.0),
+            ops: parser.ops,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn evaluate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let mut stack = [Complex::new(0.0, 0.0); MAX_STACK];
+        let mut top = 0;
+        for op in &self.ops {
+            match *op {
+                Op::Z => {
+                    stack[top] = z;
+                    top += 1
+                }
+                Op::C => {
+                    stack[top] = c;
+                    top += 1
+                }
+                Op::Constant(value) => {
+                    stack[top] = value;
+                    top += 1
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => {
+                    top -= 1;
+                    stack[top - 1] = apply_binary(*op, stack[top - 1], stack[top])
+                }
+                Op::Neg | Op::PowI(_) | Op::Function(_) => {
+                    stack[top - 1] = apply_unary(*op, stack[top - 1])
+                }
+            }
+        }
+        stack[0]
+    }
+}
+
+impl Formula for Expression {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        self.evaluate(z, c)
+    }
+
+    // Worked out from the formula when it's parsed, see degree. Formulas that don't grow like a
+    // power of z, or grow no faster than z itself, get 2 as there's no better guess
+    fn degree(&self) -> f64 {
+        self.degree
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degree_is_the_top_power_of_z() {
+        let degree = |source: &str| Expression::parse(source).unwrap().degree();
+        assert_eq!(degree("z^2 + c"), 2.0);
+        assert_eq!(degree("z^3 - z + c"), 3.0);
+        assert_eq!(degree("z*z*z*z + c"), 4.0);
+        assert_eq!(degree("2z^5/(z^2 + 1) - c"), 3.0);
+        assert_eq!(degree("z^2.5 + c"), 2.5);
+        assert_eq!(degree("sqrt(z^7) + c"), 3.5);
+        assert_eq!(degree("conj(z)^3 + c^4"), 3.0);
+        assert_eq!(degree("-(z^2) + sin(c)"), 2.0);
+        assert_eq!(degree("z^(1 + 2) + c"), 3.0);
+        // no power of z, or no faster than z, falls back to 2
+        assert_eq!(degree("sin(z)/c + z^2"), 2.0);
+        assert_eq!(degree("z^c + c"), 2.0);
+        assert_eq!(degree("z + c"), 2.0);
+        assert_eq!(degree("c"), 2.0);
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let nested = |open: &str, close: &str, levels: usize| {
+            format!("{}z{} + c", open.repeat(levels), close.repeat(levels))
+        };
+        let too_deep = |source: &str| {
+            Expression::parse(source).map_err(|error| error.message)
+                == Err("the formula is too deeply nested".to_string())
+        };
+        assert!(Expression::parse(&nested("(", ")", 150)).is_ok());
+        assert!(Expression::parse(&nested("-", "", 150)).is_ok());
+        assert!(Expression::parse(&nested("sin(", ")", 100)).is_ok());
+        assert!(too_deep(&nested("(", ")", 30000)));
+        assert!(too_deep(&nested("-", "", 30000)));
+        assert!(too_deep(&nested("-(", ")", 30000)));
+        assert!(too_deep(&nested("sqrt(", ")", 30000)));
+        assert!(too_deep(&format!("z{}", "^z".repeat(30000))));
+    }
+}
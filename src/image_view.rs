@@ -0,0 +1,141 @@
+use iced::advanced::graphics::geometry;
+use iced::advanced::image::{self, FilterMethod};
+use iced::advanced::layout::{self, Layout};
+use iced::advanced::renderer::{self, Renderer as _};
+use iced::advanced::widget::{tree, Tree};
+use iced::advanced::{mouse, Clipboard, Shell, Widget};
+use iced::widget::canvas;
+use iced::{event, Element, Length, Rectangle, Size, Transformation};
+
+// Shows an already rendered image, uploaded to the gpu in one go, with a canvas program on top
+// of it. The program gets all the mouse and keyboard events like it would in a canvas and can
+// draw extra things like borders over the image. iced can't draw images inside a canvas itself
+pub struct ImageView<'a, P: canvas::Program<Message>, Message> {
+    image: Option<image::Handle>,
+    program: &'a P,
+    width: Length,
+    height: Length,
+    message: std::marker::PhantomData<Message>,
+}
+
+impl<'a, P: canvas::Program<Message>, Message> ImageView<'a, P, Message> {
+    pub fn new(image: Option<image::Handle>, program: &'a P) -> Self {
+        ImageView {
+            image,
+            program,
+            width: Length::Fill,
+            height: Length::Fill,
+            message: std::marker::PhantomData,
+        }
+    }
+
+    pub fn width(self, width: impl Into<Length>) -> Self {
+        ImageView {
+            width: width.into(),
+            ..self
+        }
+    }
+
+    pub fn height(self, height: impl Into<Length>) -> Self {
+        ImageView {
+            height: height.into(),
+            ..self
+        }
+    }
+}
+
+impl<'a, P: canvas::Program<Message>, Message> Widget<Message, iced::Theme, iced::Renderer>
+    for ImageView<'a, P, Message>
+{
+    fn tag(&self) -> tree::Tag {
+        tree::Tag::of::<P::State>()
+    }
+
+    fn state(&self) -> tree::State {
+        tree::State::new(P::State::default())
+    }
+
+    fn size(&self) -> Size<Length> {
+        Size::new(self.width, self.height)
+    }
+
+    fn layout(
+        &self,
+        _tree: &mut Tree,
+        _renderer: &iced::Renderer,
+        limits: &layout::Limits,
+    ) -> layout::Node {
+        layout::atomic(limits, self.width, self.height)
+    }
+
+    fn on_event(
+        &mut self,
+        tree: &mut Tree,
+        event: iced::Event,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        _renderer: &iced::Renderer,
+        _clipboard: &mut dyn Clipboard,
+        shell: &mut Shell<'_, Message>,
+        _viewport: &Rectangle,
+    ) -> event::Status {
+        let event = match event {
+            iced::Event::Mouse(event) => canvas::Event::Mouse(event),
+            iced::Event::Touch(event) => canvas::Event::Touch(event),
+            iced::Event::Keyboard(event) => canvas::Event::Keyboard(event),
+            _ => return event::Status::Ignored,
+        };
+        let state = tree.state.downcast_mut::<P::State>();
+        let (status, message) = self.program.update(state, event, layout.bounds(), cursor);
+        if let Some(message) = message {
+            shell.publish(message);
+        }
+        status
+    }
+
+    fn mouse_interaction(
+        &self,
+        tree: &Tree,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        _viewport: &Rectangle,
+        _renderer: &iced::Renderer,
+    ) -> mouse::Interaction {
+        let state = tree.state.downcast_ref::<P::State>();
+        self.program
+            .mouse_interaction(state, layout.bounds(), cursor)
+    }
+
+    fn draw(
+        &self,
+        tree: &Tree,
+        renderer: &mut iced::Renderer,
+        theme: &iced::Theme,
+        _style: &renderer::Style,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        _viewport: &Rectangle,
+    ) {
+        let bounds = layout.bounds();
+        if bounds.width < 1.0 || bounds.height < 1.0 {
+            return;
+        }
+        // nearest so every pixel of the image stays a crisp pixel on screen
+        if let Some(image) = &self.image {
+            image::Renderer::draw(renderer, image.clone(), FilterMethod::Nearest, bounds);
+        }
+        let state = tree.state.downcast_ref::<P::State>();
+        renderer.with_transformation(Transformation::translate(bounds.x, bounds.y), |renderer| {
+            let layers = self.program.draw(state, renderer, theme, bounds, cursor);
+            geometry::Renderer::draw(renderer, layers);
+        });
+    }
+}
+
+impl<'a, P: canvas::Program<Message>, Message: 'a> From<ImageView<'a, P, Message>>
+    for Element<'a, Message>
+{
+    fn from(view: ImageView<'a, P, Message>) -> Self {
+        Element::new(view)
+    }
+}
@@ -0,0 +1,411 @@
+use crate::backend::{Colouring, Formula, PointData};
+use crate::colours::{distance_shade, palette_colour};
+use crate::export::STRIP_HEIGHT;
+use crate::renderer::{plane_from_params, PixelBuffer, RenderParams};
+use num::Complex;
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// The extension of iteration data files, which is how they're told apart from images
+pub const DATA_EXTENSION: &str = "mbdata";
+
+// the first line of every iteration data file, with the version of the format
+const MAGIC: &str = "mandelbrot iteration data 2";
+
+// how many bytes each pixel takes up in the file
+const PIXEL_BYTES: usize = 40;
+
+// the longest a line of the header can be, in bytes, which leaves plenty of room for custom formulas
+const MAX_LINE: u64 = 1 << 20;
+
+// Iteration data files hold everything the colour of each pixel is worked out from, so a view
+// can be coloured again with another palette without iterating anything. They start with the
+// magic line and then the view they're of as "name value" lines up to an "end" line, which is
+// only there to be read and to say how big the image is. After that every pixel follows row by
+// row as its two smooth iteration counts (NaN for points inside the set, see PointData), the real
+// and imaginary parts of its final z and its distance estimate in pixels, all little endian f64s.
+// z can get as big as the bailout squared, which is past what an f32 holds with big bailouts.
+// That's 40 bytes a pixel, so a 4000x4000 view is about 640MB
+
+pub fn is_data_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(DATA_EXTENSION))
+}
+
+// Work out the data of every pixel in a view and save it, a strip of rows at a time so only one
+// strip's worth is ever in memory. Progress is called with how many pixels are done
+pub fn save_data<F: Formula + Clone>(
+    params: &RenderParams<F>,
+    path: &Path,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Result<(), String> {
+    // written under another name first, so a file that's there is a whole one
+    let mut unfinished = path.as_os_str().to_owned();
+    unfinished.push(".part");
+    let unfinished = PathBuf::from(unfinished);
+    let written = write_data(params, &unfinished, cancelled, progress);
+    if written.is_err() {
+        let _ = fs::remove_file(&unfinished);
+        return written;
+    }
+    fs::rename(&unfinished, path)
+        .map_err(|error| format!("couldn't write {}: {}", unfinished.display(), error))
+}
+
+fn write_data<F: Formula + Clone>(
+    params: &RenderParams<F>,
+    path: &Path,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Result<(), String> {
+    let io_error = |error: std::io::Error| format!("couldn't write {}: {}", path.display(), error);
+    let mut file = BufWriter::new(File::create(path).map_err(io_error)?);
+    let julia = match params.julia {
+        Some(c) => format!("{},{}", c.re, c.im),
+        None => "none".to_string(),
+    };
+    let header = [
+        ("width", params.width.to_string()),
+        ("height", params.height.to_string()),
+        ("re", params.centre.re.to_string()),
+        ("im", params.centre.im.to_string()),
+        ("resolution", params.resolution.to_string()),
+        ("iterations", params.max_iterations.to_string()),
+        ("bailout", params.bailout.to_string()),
+        (
+            "formula",
+            format!("{:?}", params.formula).replace('\n', " "),
+        ),
+        ("julia", julia),
+        ("projection", format!("{:?}", params.projection)),
+        ("perturbation", params.perturbation.to_string()),
+    ];
+    writeln!(file, "{}", MAGIC).map_err(io_error)?;
+    for (name, value) in header {
+        writeln!(file, "{} {}", name, value).map_err(io_error)?;
+    }
+    writeln!(file, "end").map_err(io_error)?;
+
+    let plane = plane_from_params(params);
+    let (width, height) = (params.width, params.height);
+    let rows_done = AtomicU64::new(0);
+    for top in (0..height).step_by(STRIP_HEIGHT as usize) {
+        let strip = plane.rows(top, STRIP_HEIGHT.min(height - top));
+        let bytes: Vec<Vec<u8>> = (0..strip.height())
+            .into_par_iter()
+            .map(|y| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return Vec::new();
+                }
+                let row = (0..width)
+                    .flat_map(|x| encode(strip.point_data(strip.point_at(x, y))))
+                    .collect();
+                progress((rows_done.fetch_add(1, Ordering::Relaxed) + 1) * width);
+                row
+            })
+            .collect();
+        if cancelled.load(Ordering::Relaxed) {
+            return Err("the export was cancelled".to_string());
+        }
+        for row in bytes {
+            file.write_all(&row).map_err(io_error)?;
+        }
+    }
+    file.flush().map_err(io_error)
+}
+
+fn encode(data: PointData) -> [u8; PIXEL_BYTES] {
+    let mut bytes = [0; PIXEL_BYTES];
+    bytes[0..8].copy_from_slice(&data.continuous.unwrap_or(f64::NAN).to_le_bytes());
+    bytes[8..16].copy_from_slice(&data.bands.unwrap_or(f64::NAN).to_le_bytes());
+    bytes[16..24].copy_from_slice(&data.z.re.to_le_bytes());
+    bytes[24..32].copy_from_slice(&data.z.im.to_le_bytes());
+    bytes[32..40].copy_from_slice(&data.distance.to_le_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> PointData {
+    let f64_at = |at: usize| f64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    let count = |count: f64| (!count.is_nan()).then_some(count);
+    PointData {
+        continuous: count(f64_at(0)),
+        bands: count(f64_at(8)),
+        z: Complex::new(f64_at(16), f64_at(24)),
+        distance: f64_at(32),
+    }
+}
+
+// the colour of a pixel from its data, the same as the plane would have coloured it with a black
+// inside
+pub fn colour(
+    data: PointData,
+    colours: &[(u8, u8, u8)],
+    colouring: Colouring,
+    palette_offset: f64,
+) -> (u8, u8, u8) {
+    let shade = distance_shade(data.distance);
+    let scale = |channel: u8| (channel as f64 * shade).round() as u8;
+    match (colouring, data.continuous, data.bands) {
+        (Colouring::EscapeTime, Some(continuous), _) => {
+            palette_colour(colours, continuous, palette_offset)
+        }
+        (Colouring::Distance, _, Some(_)) => (scale(255), scale(255), scale(255)),
+        (Colouring::DistanceBands, _, Some(bands)) => {
+            let (r, g, b) = palette_colour(colours, bands, palette_offset);
+            (scale(r), scale(g), scale(b))
+        }
+        _ => (0, 0, 0),
+    }
+}
+
+// Colour an iteration data file, a strip of rows at a time so the file never has to all be in
+// memory at once
+pub fn recolour(
+    path: &Path,
+    colours: &[(u8, u8, u8)],
+    colouring: Colouring,
+    palette_offset: f64,
+) -> Result<PixelBuffer, String> {
+    let read_error = |error: String| format!("couldn't read {}: {}", path.display(), error);
+    let file = File::open(path).map_err(|error| read_error(error.to_string()))?;
+    let mut reader = BufReader::new(file);
+    let (width, height) = read_header(&mut reader).map_err(read_error)?;
+    // checked before anything gets allocated, as the header could say anything
+    let pixels_start = reader
+        .stream_position()
+        .map_err(|error| read_error(error.to_string()))?;
+    let length = reader
+        .get_ref()
+        .metadata()
+        .map_err(|error| read_error(error.to_string()))?
+        .len();
+    let pixel_bytes = (width as u64 * height as u64).checked_mul(PIXEL_BYTES as u64);
+    if pixel_bytes != Some(length.saturating_sub(pixels_start)) {
+        return Err(read_error(format!(
+            "it should have {}x{} pixels but it's the wrong size",
+            width, height
+        )));
+    }
+    let mut image = PixelBuffer::new(width, height);
+    let row_bytes = width as usize * PIXEL_BYTES;
+    let mut strip = vec![0; row_bytes * STRIP_HEIGHT as usize];
+    for top in (0..height).step_by(STRIP_HEIGHT as usize) {
+        let rows = STRIP_HEIGHT.min((height - top) as u64) as usize;
+        let strip = &mut strip[..rows * row_bytes];
+        reader
+            .read_exact(strip)
+            .map_err(|error| read_error(error.to_string()))?;
+        let coloured: Vec<Vec<(u8, u8, u8)>> = strip
+            .par_chunks_exact(row_bytes)
+            .map(|row| {
+                row.chunks_exact(PIXEL_BYTES)
+                    .map(|pixel| colour(decode(pixel), colours, colouring, palette_offset))
+                    .collect()
+            })
+            .collect();
+        for (y, row) in coloured.into_iter().enumerate() {
+            for (x, pixel) in row.into_iter().enumerate() {
+                image.set(x as u32, top + y as u32, pixel);
+            }
+        }
+    }
+    Ok(image)
+}
+
+// the size of the view in a file, leaving the reader at the start of the pixels
+fn read_header(reader: &mut impl BufRead) -> Result<(u32, u32), String> {
+    let mut line = String::new();
+    let mut next_line = |line: &mut String| {
+        line.clear();
+        // so something that isn't a header doesn't all get read into memory looking for the end of a line
+        match reader.take(MAX_LINE).read_line(line) {
+            Ok(0) => Err("it ends before the pixels start".to_string()),
+            Ok(_) if !line.ends_with('\n') => Err("it isn't an iteration data file".to_string()),
+            Ok(_) => Ok(()),
+            Err(error) => Err(error.to_string()),
+        }
+    };
+    next_line(&mut line)?;
+    if line.trim_end() != MAGIC {
+        return Err("it isn't an iteration data file".to_string());
+    }
+    let (mut width, mut height) = (None, None);
+    loop {
+        next_line(&mut line)?;
+        let line = line.trim_end();
+        if line == "end" {
+            break;
+        }
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        match name {
+            "width" => width = value.parse().ok(),
+            "height" => height = value.parse().ok(),
+            _ => {}
+        }
+    }
+    match (width, height) {
+        (Some(0), Some(_)) | (Some(_), Some(0)) => {
+            Err("the view it's of has no pixels".to_string())
+        }
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err("it doesn't say how big the view is".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Mandelbrot;
+    use crate::colours::RAINBOW_COLOURS;
+    use crate::precision::{ComplexDecimal, Decimal};
+    use crate::renderer::render_from_params;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "mandelbrot-{}-{}.{}",
+            name,
+            std::process::id(),
+            DATA_EXTENSION
+        ))
+    }
+
+    #[test]
+    fn recolours_like_rendering() {
+        let path = temp_path("view");
+        let params = RenderParams::<Mandelbrot> {
+            centre: ComplexDecimal::new("-0.745".parse().unwrap(), "0.1".parse().unwrap()),
+            resolution: Decimal::from_f64(1e-4, 0),
+            max_iterations: 500,
+            width: 64,
+            height: 48,
+            ..Default::default()
+        };
+        save_data(&params, &path, &AtomicBool::new(false), |_| {}).unwrap();
+        for colouring in Colouring::ALL {
+            let params = RenderParams {
+                colouring,
+                palette_offset: 0.3,
+                ..params.clone()
+            };
+            let rendered = render_from_params(&params, &RAINBOW_COLOURS);
+            let recoloured = recolour(&path, &RAINBOW_COLOURS, colouring, 0.3).unwrap();
+            assert_eq!((recoloured.width(), recoloured.height()), (64, 48));
+            assert!(
+                rendered.pixels() == recoloured.pixels(),
+                "{:?} came out differently",
+                colouring
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    // With a big bailout z gets far past what an f32 can hold, and everything still comes back
+    // exactly as it was saved
+    #[test]
+    fn keeps_big_orbits() {
+        let path = temp_path("bailout");
+        let params = RenderParams::<Mandelbrot> {
+            centre: ComplexDecimal::new("-0.745".parse().unwrap(), "0.1".parse().unwrap()),
+            resolution: Decimal::from_f64(1e-4, 0),
+            max_iterations: 500,
+            width: 32,
+            height: 24,
+            bailout: 1e30,
+            ..Default::default()
+        };
+        save_data(&params, &path, &AtomicBool::new(false), |_| {}).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let pixels = &bytes[bytes.len() - 32 * 24 * PIXEL_BYTES..];
+        let plane = plane_from_params(&params);
+        let mut escaped = 0;
+        for (i, pixel) in pixels.chunks_exact(PIXEL_BYTES).enumerate() {
+            let expected = plane.point_data(plane.point_at(i as u64 % 32, i as u64 / 32));
+            let saved = decode(pixel);
+            assert_eq!(saved.continuous, expected.continuous);
+            assert_eq!(saved.bands, expected.bands);
+            assert_eq!(saved.z, expected.z);
+            assert_eq!(saved.distance.to_bits(), expected.distance.to_bits());
+            escaped +=
+                (expected.continuous.is_some() && expected.z.norm() > f32::MAX as f64) as u32;
+        }
+        assert!(escaped > 0, "nothing got past what an f32 holds");
+        for colouring in Colouring::ALL {
+            let rendered = render_from_params(
+                &RenderParams {
+                    colouring,
+                    ..params.clone()
+                },
+                &RAINBOW_COLOURS,
+            );
+            let recoloured = recolour(&path, &RAINBOW_COLOURS, colouring, 0.0).unwrap();
+            assert!(
+                rendered.pixels() == recoloured.pixels(),
+                "{:?} came out differently",
+                colouring
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        let path = temp_path("broken");
+        let recoloured = |contents: &[u8]| {
+            fs::write(&path, contents).unwrap();
+            recolour(&path, &RAINBOW_COLOURS, Colouring::EscapeTime, 0.0).map(|_| ())
+        };
+        let file = |width: u32, height: u32, pixels: usize| {
+            let mut file =
+                format!("{}\nwidth {}\nheight {}\nend\n", MAGIC, width, height).into_bytes();
+            file.resize(file.len() + pixels * PIXEL_BYTES, 0);
+            file
+        };
+        let error = |message: &str| Err(format!("couldn't read {}: {}", path.display(), message));
+        assert_eq!(recoloured(&file(3, 2, 6)), Ok(()));
+        assert_eq!(
+            recoloured(&file(0, 2, 0)),
+            error("the view it's of has no pixels")
+        );
+        assert_eq!(
+            recoloured(&file(3, 0, 0)),
+            error("the view it's of has no pixels")
+        );
+        let wrong_size = |width, height| {
+            error(&format!(
+                "it should have {}x{} pixels but it's the wrong size",
+                width, height
+            ))
+        };
+        assert_eq!(
+            recoloured(&file(4_000_000, 4_000_000, 6)),
+            wrong_size(4_000_000, 4_000_000)
+        );
+        assert_eq!(
+            recoloured(&file(u32::MAX, u32::MAX, 0)),
+            wrong_size(u32::MAX, u32::MAX)
+        );
+        assert_eq!(recoloured(&file(3, 2, 5)), wrong_size(3, 2));
+        assert_eq!(recoloured(&file(3, 2, 7)), wrong_size(3, 2));
+        assert_eq!(
+            recoloured(b"a png"),
+            error("it isn't an iteration data file")
+        );
+        assert_eq!(
+            recoloured(&vec![b'x'; MAX_LINE as usize * 2]),
+            error("it isn't an iteration data file")
+        );
+        assert_eq!(
+            recoloured(format!("{}\nwidth 3\n", MAGIC).as_bytes()),
+            error("it ends before the pixels start")
+        );
+        assert_eq!(
+            recoloured(format!("{}\nend\n", MAGIC).as_bytes()),
+            error("it doesn't say how big the view is")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use crate::animated::Playback;
+use crate::animation::{export_animation, Animation};
+use crate::backend::{Formula, MandelbrotPlane};
+use crate::export::export_image;
+use crate::iteration_data::save_data;
+use crate::renderer::{
+    plane_from_params, render_plane_guessing, render_plane_in_passes, PixelBuffer, RenderParams,
+    PROGRESSIVE_STEP,
+};
+use crate::supersampling::supersample;
+use crate::tiles::{can_tile, render_tiles, RenderedTile, TileCache};
+use iced::futures::channel::mpsc;
+use iced::futures::Stream;
+use image::ImageFormat;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(0);
+
+// What a render job has to say while it's running
+#[derive(Debug, Clone)]
+pub enum JobEvent<F: Formula> {
+    // how much of the image has been rendered, from 0 to 1
+    Progress(f32),
+    // a rough version of the image while it's being rendered progressively
+    Pass(PixelBuffer),
+    // which tiles the image was put together from, if it was rendered out of tiles
+    Tiles(Vec<RenderedTile>),
+    Finished(MandelbrotPlane<F>, PixelBuffer),
+    // how an export straight to a file went, instead of Finished
+    Saved(Result<(), String>),
+}
+
+// A render running on its own thread. It reports back through the stream it was started with,
+// which just ends without finishing if the job gets cancelled
+#[derive(Debug)]
+pub struct RenderJob {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RenderJob {
+    // A progressive job sends a rougher image after each pass before the finished one.
+    // Given a tile cache, the job renders out of tiles whenever it can
+    pub fn start<F: Formula + Clone + PartialEq + Send + 'static>(
+        params: RenderParams<F>,
+        colours: &'static [(u8, u8, u8)],
+        progressive: bool,
+        tiles: Option<Arc<Mutex<TileCache<F>>>>,
+    ) -> (RenderJob, impl Stream<Item = JobEvent<F>>) {
+        let job = RenderJob {
+            id: NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        let (sender, receiver) = mpsc::unbounded();
+        let cancelled = job.cancelled.clone();
+        std::thread::spawn(move || {
+            // the reference orbit for deep zooms can take a while too, so it's done here as well
+            let plane = plane_from_params(&params);
+            let pixels = (params.width * params.height).max(1);
+            // only send whole percentages, there's no point flooding the ui with updates
+            let percent = AtomicU64::new(0);
+            let progress = |pixels_done: u64| {
+                let now = pixels_done * 100 / pixels;
+                if percent.fetch_max(now, Ordering::Relaxed) < now {
+                    let _ = sender.unbounded_send(JobEvent::Progress(now as f32 / 100.0));
+                }
+            };
+            let on_pass = |buffer: &PixelBuffer| {
+                let _ = sender.unbounded_send(JobEvent::Pass(buffer.clone()));
+            };
+            let first_step = if progressive { PROGRESSIVE_STEP } else { 1 };
+            let supersampling = params.supersampling;
+            let base_progress = |pixels_done| progress(supersampling.base_progress(pixels_done));
+            let buffer = if let Some(cache) = tiles.filter(|_| can_tile(&params, &plane)) {
+                render_tiles(
+                    &params, &plane, colours, &cache, &cancelled, progress, on_pass,
+                )
+                .map(|(buffer, tiles)| {
+                    let _ = sender.unbounded_send(JobEvent::Tiles(tiles));
+                    buffer
+                })
+            } else if params.guessing {
+                render_plane_guessing(&plane, colours, &cancelled, base_progress)
+            } else {
+                render_plane_in_passes(
+                    &plane,
+                    colours,
+                    first_step,
+                    &cancelled,
+                    base_progress,
+                    on_pass,
+                )
+            };
+            // the image with one sample per pixel is worth showing while the rest are taken
+            let buffer = buffer.and_then(|buffer| {
+                if !supersampling.enabled() {
+                    return Some(buffer);
+                }
+                if progressive {
+                    on_pass(&buffer);
+                }
+                supersample(&plane, colours, supersampling, buffer, &cancelled, progress)
+            });
+            if let Some(buffer) = buffer {
+                let _ = sender.unbounded_send(JobEvent::Finished(plane, buffer));
+            }
+        });
+        (job, receiver)
+    }
+
+    // Render to a file instead of sending the image back, see export::export_image.
+    // Only progress and then whether it saved come out of the stream
+    pub fn export<F: Formula + Clone + Send + 'static>(
+        params: RenderParams<F>,
+        colours: &'static [(u8, u8, u8)],
+        path: PathBuf,
+        format: ImageFormat,
+    ) -> (RenderJob, impl Stream<Item = JobEvent<F>>) {
+        RenderJob::save(params.width * params.height, move |cancelled, progress| {
+            export_image(&params, colours, &path, format, cancelled, progress)
+        })
+    }
+
+    // Save the iteration data of a view instead of its colours, see iteration_data::save_data
+    pub fn export_data<F: Formula + Clone + Send + 'static>(
+        params: RenderParams<F>,
+        path: PathBuf,
+    ) -> (RenderJob, impl Stream<Item = JobEvent<F>>) {
+        RenderJob::save(params.width * params.height, move |cancelled, progress| {
+            save_data(&params, &path, cancelled, progress)
+        })
+    }
+
+    // Render every frame of an animation into a folder or an animated file on another thread,
+    // in the same way as an export. Saved is sent once all of them are there
+    pub fn animate<F: Formula + Clone + Send + 'static>(
+        animation: Animation,
+        base: RenderParams<F>,
+        colours: &'static [(u8, u8, u8)],
+        path: PathBuf,
+        playback: Playback,
+    ) -> (RenderJob, impl Stream<Item = JobEvent<F>>) {
+        let pixels = animation.frames() * base.width * base.height;
+        RenderJob::save(pixels, move |cancelled, progress| {
+            export_animation(
+                &animation, &base, colours, &path, playback, cancelled, progress,
+            )
+        })
+    }
+
+    // Save something on another thread, sending how far through pixels pixels it is
+    // and then whether it saved, unless it gets cancelled first
+    fn save<F: Formula + Send + 'static>(
+        pixels: u64,
+        save: impl FnOnce(&AtomicBool, &(dyn Fn(u64) + Sync)) -> Result<(), String> + Send + 'static,
+    ) -> (RenderJob, impl Stream<Item = JobEvent<F>>) {
+        let job = RenderJob {
+            id: NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        let (sender, receiver) = mpsc::unbounded();
+        let cancelled = job.cancelled.clone();
+        std::thread::spawn(move || {
+            let pixels = pixels.max(1);
+            let percent = AtomicU64::new(0);
+            let progress = |pixels_done: u64| {
+                let now = pixels_done * 100 / pixels;
+                if percent.fetch_max(now, Ordering::Relaxed) < now {
+                    let _ = sender.unbounded_send(JobEvent::Progress(now as f32 / 100.0));
+                }
+            };
+            let saved = save(&cancelled, &progress);
+            if !cancelled.load(Ordering::Relaxed) {
+                let _ = sender.unbounded_send(JobEvent::Saved(saved));
+            }
+        });
+        (job, receiver)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // stop the job as soon as possible, nothing else will come out of its stream
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed)
+    }
+}
@@ -1,23 +1,80 @@
 #![allow(dead_code)]
+mod animated;
+mod animation;
 mod backend;
+mod cli;
 mod colours;
+mod exponential_map;
+mod export;
+mod expression;
+mod image_view;
+mod iteration_data;
+mod jobs;
+mod precision;
 mod renderer;
+mod simd;
+mod supersampling;
+mod tiles;
 
+use crate::animated::Playback;
+use crate::animation::{Animation, Keyframe};
+use crate::backend::{
+    Buffalo, BurningShip, Celtic, Colouring, Formula, InteriorChecks, Mandelbrot, MandelbrotPlane,
+    Multibrot, Projection, RealMultibrot, Tricorn, DEFAULT_BAILOUT,
+};
+use crate::cli::{Cli, CliCommand};
 use crate::colours::*;
-use crate::renderer::mandelbrot_from_params_parallel;
+use crate::export::strips_folder;
+use crate::expression::Expression;
+use crate::image_view::ImageView;
+use crate::iteration_data::{is_data_path, DATA_EXTENSION};
+use crate::jobs::{JobEvent, RenderJob};
+use crate::precision::{ComplexDecimal, Decimal};
+use crate::renderer::{PixelBuffer, RenderParams};
+use crate::supersampling::{SamplePattern, Supersampling};
+use crate::tiles::{RenderedTile, TileCache, TILE_CACHE_SIZE, TILE_SIZE};
+use clap::Parser;
 use iced::event::Status;
 use iced::mouse::Cursor;
 use iced::widget::canvas::Event;
-use iced::widget::{button, canvas, column, pick_list, row, slider, text};
-use iced::{Element, Length, Point, Rectangle, Sandbox, Settings, Size};
-use num::complex::ComplexFloat;
+use iced::widget::{
+    button, canvas, checkbox, column, image, pick_list, progress_bar, row, slider, text,
+    text_input, Space,
+};
+use iced::{
+    executor, keyboard, Application, Command, Element, Length, Point, Rectangle, Settings,
+    Subscription, Theme,
+};
 use num::Complex;
+use std::collections::VecDeque;
 use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 const CANVAS_SIZE: u16 = 500; // square canvas
+const PREVIEW_SIZE: u16 = 150; // square julia preview
+const PREVIEW_MAX_ITERATIONS: u64 = 500; // keeps the preview quick enough to follow the cursor
+const DEFAULT_EXPRESSION: &str = "z^3 - z + c";
+const FRAME_CACHE_SIZE: usize = 16; // recent views kept around so going back to them is instant
+const KEYFRAME_SPACING: u64 = 60; // frames between keyframes unless it's changed
 
 fn main() -> iced::Result {
-    MandelbrotExplorer::run(Settings::default())
+    match Cli::parse().command {
+        Some(command) => {
+            let done = match command {
+                CliCommand::Render(args) => cli::render(args),
+                CliCommand::Animate(args) => cli::animate(args),
+                CliCommand::ZoomFrames(args) => cli::zoom_frames(args),
+                CliCommand::Recolour(args) => cli::recolour(args),
+            };
+            if let Err(error) = done {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        None => MandelbrotExplorer::run(Settings::default()),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -25,9 +82,54 @@ enum Message {
     ZoomIn(Point),
     ZoomOut(Point),
     IterationSet(u32),
+    BailoutSet(i32),
+    PaletteOffsetSet(f64),
     Refresh,
+    ExportPanelToggled,
+    ExportWidthEdited(String),
+    ExportHeightEdited(String),
+    ExportMatchViewToggled(bool),
+    ExportPathEdited(String),
+    ChooseExportPath,
+    ExportPathChosen(Option<PathBuf>),
+    ExportFormatSelected(ExportFormat),
     RenderImage,
+    OverwriteConfirmed,
+    OverwriteCancelled,
+    AnimationPanelToggled,
+    KeyframeFrameEdited(String),
+    AddKeyframe,
+    ClearKeyframes,
+    AnimationScrubbed(u32),
+    GoToFrame,
+    AnimationWidthEdited(String),
+    AnimationHeightEdited(String),
+    AnimationFolderEdited(String),
+    AnimationDelayEdited(String),
+    AnimationLoopsEdited(String),
+    CyclePalette,
+    RenderAnimation,
     ColourSelected(Colour),
+    ColouringSelected(Colouring),
+    InteriorColouringSelected(InteriorColouring),
+    PerturbationToggled(bool),
+    JuliaToggled(bool),
+    InteriorChecksSet(InteriorChecks),
+    GuessingToggled(bool),
+    SimdToggled(bool),
+    TileOverlayToggled(bool),
+    SupersamplingSet(Supersampling),
+    PickJulia(Point),
+    CursorMoved(Point),
+    FractalSelected(Fractal),
+    PowerSet(f64),
+    ExpressionEdited(String),
+    ExpressionSubmitted,
+    CanvasRender(u64, JobEvent<Fractal>),
+    ExportRender(u64, JobEvent<Fractal>),
+    JuliaPreviewRender(u64, JobEvent<Fractal>),
+    AnimationPreviewRender(u64, JobEvent<Fractal>),
+    CancelExport,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -58,82 +160,734 @@ impl std::fmt::Display for Colour {
         )
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Tiff,
+    // the iteration data of every pixel instead of its colour, see iteration_data
+    Data,
+}
+impl ExportFormat {
+    const ALL: [ExportFormat; 5] = [
+        ExportFormat::Png,
+        ExportFormat::Jpeg,
+        ExportFormat::Bmp,
+        ExportFormat::Tiff,
+        ExportFormat::Data,
+    ];
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::Bmp => "bmp",
+            ExportFormat::Tiff => "tiff",
+            ExportFormat::Data => DATA_EXTENSION,
+        }
+    }
+
+    fn image_format(self) -> Option<::image::ImageFormat> {
+        match self {
+            ExportFormat::Png => Some(::image::ImageFormat::Png),
+            ExportFormat::Jpeg => Some(::image::ImageFormat::Jpeg),
+            ExportFormat::Bmp => Some(::image::ImageFormat::Bmp),
+            ExportFormat::Tiff => Some(::image::ImageFormat::Tiff),
+            ExportFormat::Data => None,
+        }
+    }
+
+    // the format a file name's extension is for, if it's one of these
+    fn from_path(path: &Path) -> Option<ExportFormat> {
+        if is_data_path(path) {
+            return Some(ExportFormat::Data);
+        }
+        let format = ::image::ImageFormat::from_path(path).ok()?;
+        ExportFormat::ALL
+            .into_iter()
+            .find(|export| export.image_format() == Some(format))
+    }
+}
+impl std::fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ExportFormat::Png => "PNG",
+                ExportFormat::Jpeg => "JPEG",
+                ExportFormat::Bmp => "BMP",
+                ExportFormat::Tiff => "TIFF",
+                ExportFormat::Data => "Iteration data",
+            }
+        )
+    }
+}
+
+// What the export panel is set to. The sizes are kept as they were typed, so they can be
+// edited freely and only need to make sense when the export starts
+#[derive(Debug)]
+struct ExportSettings {
+    open: bool,
+    width: String,
+    height: String,
+    // keep the image the same shape as the canvas, otherwise the height shows more or less
+    // of the plane above and below what the canvas does
+    match_view: bool,
+    path: String,
+    format: ExportFormat,
+    // waiting to be told it's fine to replace a file that's already there
+    confirm_overwrite: bool,
+}
+
+impl Default for ExportSettings {
+    fn default() -> ExportSettings {
+        ExportSettings {
+            open: false,
+            width: "4000".to_string(),
+            height: "4000".to_string(),
+            match_view: true,
+            path: "output.png".to_string(),
+            format: ExportFormat::Png,
+            confirm_overwrite: false,
+        }
+    }
+}
+
+impl ExportSettings {
+    fn size(&self) -> Result<(u64, u64), String> {
+        parse_size(&self.width, &self.height)
+    }
+}
+
+// What the animation panel is set to, with the frame the next keyframe goes on
+// and the frame being previewed
+#[derive(Debug)]
+struct AnimationSettings {
+    open: bool,
+    frame: String,
+    scrub: u32,
+    preview: Option<image::Handle>,
+    preview_job: Option<RenderJob>,
+    width: String,
+    height: String,
+    // a folder for numbered PNGs, or a .gif or .png for a single animated file
+    folder: String,
+    delay: String,
+    loops: String,
+}
+
+impl Default for AnimationSettings {
+    fn default() -> AnimationSettings {
+        AnimationSettings {
+            open: false,
+            frame: "0".to_string(),
+            scrub: 0,
+            preview: None,
+            preview_job: None,
+            width: "1280".to_string(),
+            height: "720".to_string(),
+            folder: "frames".to_string(),
+            delay: Playback::default().delay.to_string(),
+            loops: Playback::default().loops.to_string(),
+        }
+    }
+}
+
+impl AnimationSettings {
+    fn size(&self) -> Result<(u64, u64), String> {
+        parse_size(&self.width, &self.height)
+    }
+
+    fn playback(&self) -> Result<Playback, String> {
+        Ok(Playback {
+            delay: self
+                .delay
+                .trim()
+                .parse()
+                .map_err(|_| "The delay needs to be a whole number of milliseconds")?,
+            loops: self
+                .loops
+                .trim()
+                .parse()
+                .map_err(|_| "The loops need to be a whole number, 0 for forever")?,
+        })
+    }
+}
+
+// a size typed in as a width and height, which both have to be at least a pixel
+fn parse_size(width: &str, height: &str) -> Result<(u64, u64), String> {
+    let parse = |name: &str, size: &str| match size.trim().parse::<u64>() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(format!("The {} needs to be a whole number of pixels", name)),
+    };
+    Ok((parse("width", width)?, parse("height", height)?))
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+enum Fractal {
+    #[default]
+    Mandelbrot,
+    Multibrot(u32),
+    RealMultibrot(f64),
+    BurningShip,
+    Tricorn,
+    Celtic,
+    Buffalo,
+    Custom(Arc<Expression>),
+}
+impl Fractal {
+    // every fractal, with the multibrots using the given power
+    // and the user's own formula if they have entered one
+    fn all(power: f64, custom: Option<&Arc<Expression>>) -> Vec<Fractal> {
+        let mut fractals = vec![
+            Fractal::Mandelbrot,
+            Fractal::Multibrot((power.round() as u32).max(2)),
+            Fractal::RealMultibrot(power),
+            Fractal::BurningShip,
+            Fractal::Tricorn,
+            Fractal::Celtic,
+            Fractal::Buffalo,
+        ];
+        fractals.extend(custom.cloned().map(Fractal::Custom));
+        fractals
+    }
+
+    fn with_power(self, power: f64) -> Fractal {
+        match self {
+            Fractal::Multibrot(_) => Fractal::Multibrot((power.round() as u32).max(2)),
+            Fractal::RealMultibrot(_) => Fractal::RealMultibrot(power),
+            fractal => fractal,
+        }
+    }
+}
+impl Formula for Fractal {
+    fn iterate(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        match self {
+            Fractal::Mandelbrot => Mandelbrot.iterate(z, c),
+            Fractal::Multibrot(power) => Multibrot(*power).iterate(z, c),
+            Fractal::RealMultibrot(power) => RealMultibrot(*power).iterate(z, c),
+            Fractal::BurningShip => BurningShip.iterate(z, c),
+            Fractal::Tricorn => Tricorn.iterate(z, c),
+            Fractal::Celtic => Celtic.iterate(z, c),
+            Fractal::Buffalo => Buffalo.iterate(z, c),
+            Fractal::Custom(expression) => expression.iterate(z, c),
+        }
+    }
+
+    fn is_mandelbrot(&self) -> bool {
+        *self == Fractal::Mandelbrot
+    }
+
+    fn degree(&self) -> f64 {
+        match self {
+            Fractal::Mandelbrot => Mandelbrot.degree(),
+            Fractal::Multibrot(power) => Multibrot(*power).degree(),
+            Fractal::RealMultibrot(power) => RealMultibrot(*power).degree(),
+            Fractal::BurningShip => BurningShip.degree(),
+            Fractal::Tricorn => Tricorn.degree(),
+            Fractal::Celtic => Celtic.degree(),
+            Fractal::Buffalo => Buffalo.degree(),
+            Fractal::Custom(expression) => expression.degree(),
+        }
+    }
+
+    fn derivative(
+        &self,
+        z: Complex<f64>,
+        c: Complex<f64>,
+        dz: Complex<f64>,
+        dc: Complex<f64>,
+    ) -> Complex<f64> {
+        match self {
+            Fractal::Mandelbrot => Mandelbrot.derivative(z, c, dz, dc),
+            Fractal::Multibrot(power) => Multibrot(*power).derivative(z, c, dz, dc),
+            Fractal::RealMultibrot(power) => RealMultibrot(*power).derivative(z, c, dz, dc),
+            Fractal::BurningShip => BurningShip.derivative(z, c, dz, dc),
+            Fractal::Tricorn => Tricorn.derivative(z, c, dz, dc),
+            Fractal::Celtic => Celtic.derivative(z, c, dz, dc),
+            Fractal::Buffalo => Buffalo.derivative(z, c, dz, dc),
+            Fractal::Custom(expression) => expression.derivative(z, c, dz, dc),
+        }
+    }
+}
+impl std::fmt::Display for Fractal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fractal::Mandelbrot => write!(f, "Mandelbrot"),
+            Fractal::Multibrot(power) => write!(f, "Multibrot z^{} + c", power),
+            Fractal::RealMultibrot(power) => write!(f, "Multibrot z^{:.2} + c", power),
+            Fractal::BurningShip => write!(f, "Burning Ship"),
+            Fractal::Tricorn => write!(f, "Tricorn"),
+            Fractal::Celtic => write!(f, "Celtic"),
+            Fractal::Buffalo => write!(f, "Buffalo"),
+            Fractal::Custom(expression) => write!(f, "Custom: {}", expression),
+        }
+    }
+}
 struct MandelbrotExplorer {
     set: MandelbrotSet,
+    // a small preview of the julia set for whichever point the cursor is over,
+    // and the render of the one it's over now
+    preview: Option<image::Handle>,
+    preview_job: Option<RenderJob>,
+    // the big image being rendered, where it's going, and how it went
+    export_settings: ExportSettings,
+    export: Option<(RenderJob, PathBuf)>,
+    export_progress: f32,
+    export_status: String,
+    // the keyframes of a zoom, which render into a folder in the export's place
+    animation: Animation,
+    animation_settings: AnimationSettings,
 }
 
-impl Sandbox for MandelbrotExplorer {
+impl Application for MandelbrotExplorer {
+    type Executor = executor::Default;
     type Message = Message;
+    type Theme = Theme;
+    type Flags = ();
 
-    fn new() -> Self {
-        Self {
-            set: MandelbrotSet::new(CANVAS_SIZE),
-        }
+    fn new(_flags: ()) -> (Self, Command<Message>) {
+        let mut set = MandelbrotSet::new(CANVAS_SIZE);
+        let command = set.refresh();
+        let explorer = MandelbrotExplorer {
+            set,
+            preview: None,
+            preview_job: None,
+            export_settings: ExportSettings::default(),
+            export: None,
+            export_progress: 0.0,
+            export_status: String::new(),
+            animation: Animation::default(),
+            animation_settings: AnimationSettings::default(),
+        };
+        (explorer, command)
     }
 
     fn title(&self) -> String {
         "Mandelbrot Set Explorer".to_string()
     }
 
-    fn update(&mut self, message: Self::Message) {
+    fn update(&mut self, message: Self::Message) -> Command<Message> {
         // stops floating point inaccuracies being visible in image, but don't know how much performance impact this has
-        self.set.resolution = self.set.resolution.clamp(2_f64.powi(-53), f64::MAX);
+        // perturbation only needs the pixel offsets to fit in an f64, so it can keep going much deeper
+        let deep_zoom =
+            self.set.perturbation && self.set.julia.is_none() && self.set.fractal.is_mandelbrot();
+        let min_resolution = if deep_zoom {
+            f64::MIN_POSITIVE
+        } else {
+            2_f64.powi(-53)
+        };
+        if self.set.resolution.to_f64() < min_resolution {
+            self.set.resolution = Decimal::from_f64(min_resolution, 0);
+        }
         match message {
             Message::ZoomIn(point) => {
-                self.set.centre += Complex::new(
-                    (point.x as f64 - (CANVAS_SIZE as f64 / 2.0)) * self.set.resolution,
-                    (point.y as f64 - (CANVAS_SIZE as f64 / 2.0)) * self.set.resolution,
-                );
-                self.set.resolution *= 0.5;
-                self.set.cache.clear()
+                self.set.move_centre(point);
+                self.set.resolution = self
+                    .set
+                    .resolution
+                    .mul_exact(&Decimal::from_f64(0.5, 0))
+                    .normalised();
+                self.set.refresh()
             }
             Message::ZoomOut(point) => {
-                self.set.centre += Complex::new(
-                    (point.x as f64 - (CANVAS_SIZE as f64 / 2.0)) * self.set.resolution,
-                    (point.y as f64 - (CANVAS_SIZE as f64 / 2.0)) * self.set.resolution,
-                );
-                self.set.resolution *= 2.0;
-                self.set.cache.clear()
-            }
-            Message::IterationSet(num) => self.set.max_iterations = num as u64,
-            Message::Refresh => self.set.cache.clear(),
+                self.set.move_centre(point);
+                self.set.resolution = self
+                    .set
+                    .resolution
+                    .mul_exact(&Decimal::from_f64(2.0, 0))
+                    .normalised();
+                self.set.refresh()
+            }
+            Message::IterationSet(num) => {
+                self.set.max_iterations = num as u64;
+                Command::none()
+            }
+            Message::BailoutSet(power) => {
+                self.set.bailout = 2f64.powi(power);
+                Command::none()
+            }
+            Message::PaletteOffsetSet(offset) => {
+                self.set.palette_offset = offset;
+                Command::none()
+            }
+            Message::Refresh => self.set.refresh(),
+            Message::ExportPanelToggled => {
+                self.export_settings.open = !self.export_settings.open;
+                self.export_settings.confirm_overwrite = false;
+                Command::none()
+            }
+            // the canvas is square, so matching it just means the same width and height
+            Message::ExportWidthEdited(width) => {
+                if self.export_settings.match_view {
+                    self.export_settings.height = width.clone();
+                }
+                self.export_settings.width = width;
+                Command::none()
+            }
+            Message::ExportHeightEdited(height) => {
+                if self.export_settings.match_view {
+                    self.export_settings.width = height.clone();
+                }
+                self.export_settings.height = height;
+                Command::none()
+            }
+            Message::ExportMatchViewToggled(match_view) => {
+                self.export_settings.match_view = match_view;
+                if match_view {
+                    self.export_settings.height = self.export_settings.width.clone();
+                }
+                Command::none()
+            }
+            Message::ExportPathEdited(path) => {
+                self.set_export_path(path);
+                Command::none()
+            }
+            Message::ChooseExportPath => {
+                let format = self.export_settings.format;
+                let path = PathBuf::from(&self.export_settings.path);
+                let mut dialog = rfd::AsyncFileDialog::new()
+                    .add_filter(format.to_string(), &[format.extension()]);
+                if let Some(name) = path.file_name() {
+                    dialog = dialog.set_file_name(name.to_string_lossy());
+                }
+                if let Some(directory) = path.parent().filter(|parent| parent.is_dir()) {
+                    dialog = dialog.set_directory(directory);
+                }
+                let choose = async move {
+                    let file = dialog.save_file().await?;
+                    Some(file.path().to_path_buf())
+                };
+                Command::perform(choose, Message::ExportPathChosen)
+            }
+            Message::ExportPathChosen(path) => {
+                if let Some(path) = path {
+                    self.set_export_path(path.display().to_string());
+                }
+                Command::none()
+            }
+            Message::ExportFormatSelected(format) => {
+                self.export_settings.format = format;
+                self.export_settings.path = Path::new(&self.export_settings.path)
+                    .with_extension(format.extension())
+                    .display()
+                    .to_string();
+                self.export_settings.confirm_overwrite = false;
+                Command::none()
+            }
+            // check before rendering anything that it's alright to replace the file
             Message::RenderImage => {
-                let centre = self.set.centre;
-                let resolution = self.set.resolution;
-                let max_iterations = self.set.max_iterations;
-                let colour = self.set.colour.unwrap_or(Colour::Default).to_array();
-                std::thread::spawn(move || {
-                    let points = mandelbrot_from_params_parallel(
-                        centre,
-                        resolution / 8.0,
-                        max_iterations,
-                        4000,
-                        4000,
-                        colour,
-                    );
-                    let mut image = image::RgbImage::new(4000, 4000);
-                    for point in points {
-                        image.put_pixel(
-                            point.0 .0 as u32,
-                            point.0 .1 as u32,
-                            image::Rgb(point.1.into()),
-                        )
-                    }
-                    let _ = image.save("output.png");
-                });
+                if Path::new(&self.export_settings.path).exists() {
+                    self.export_settings.confirm_overwrite = true;
+                    return Command::none();
+                }
+                self.start_export()
+            }
+            Message::OverwriteConfirmed => self.start_export(),
+            Message::OverwriteCancelled => {
+                self.export_settings.confirm_overwrite = false;
+                Command::none()
+            }
+            Message::AnimationPanelToggled => {
+                self.animation_settings.open = !self.animation_settings.open;
+                Command::none()
+            }
+            Message::KeyframeFrameEdited(frame) => {
+                self.animation_settings.frame = frame;
+                Command::none()
+            }
+            // the current view becomes a keyframe, and the next one goes a bit further on
+            Message::AddKeyframe => {
+                let Ok(frame) = self.animation_settings.frame.trim().parse::<u64>() else {
+                    return Command::none();
+                };
+                self.animation.insert(self.keyframe(frame));
+                self.animation_settings.frame = (frame + KEYFRAME_SPACING).to_string();
+                self.animation_settings.scrub = frame as u32;
+                self.animation_preview()
+            }
+            // the typed frame number is how long the loop is
+            Message::CyclePalette => {
+                let frames = match self.animation_settings.frame.trim().parse::<u64>() {
+                    Ok(frames) if frames > 1 => frames,
+                    _ => return Command::none(),
+                };
+                self.animation = Animation::palette_cycle(self.keyframe(0), frames);
+                self.animation_settings.scrub = 0;
+                self.animation_preview()
+            }
+            Message::ClearKeyframes => {
+                self.animation.clear();
+                self.animation_settings.frame = "0".to_string();
+                self.animation_settings.scrub = 0;
+                self.animation_settings.preview = None;
+                if let Some(job) = self.animation_settings.preview_job.take() {
+                    job.cancel()
+                }
+                Command::none()
+            }
+            Message::AnimationScrubbed(frame) => {
+                self.animation_settings.scrub = frame;
+                self.animation_preview()
             }
+            Message::GoToFrame => {
+                let Some(view) = self.animation.view_at(self.animation_settings.scrub as u64)
+                else {
+                    return Command::none();
+                };
+                let params = view.params(&self.set.params(CANVAS_SIZE as u64, CANVAS_SIZE as u64));
+                self.set.centre = params.centre;
+                self.set.resolution = params.resolution;
+                self.set.max_iterations = params.max_iterations;
+                self.set.palette_offset = params.palette_offset;
+                self.set.refresh()
+            }
+            Message::AnimationWidthEdited(width) => {
+                self.animation_settings.width = width;
+                Command::none()
+            }
+            Message::AnimationHeightEdited(height) => {
+                self.animation_settings.height = height;
+                Command::none()
+            }
+            Message::AnimationFolderEdited(folder) => {
+                self.animation_settings.folder = folder;
+                Command::none()
+            }
+            Message::AnimationDelayEdited(delay) => {
+                self.animation_settings.delay = delay;
+                Command::none()
+            }
+            Message::AnimationLoopsEdited(loops) => {
+                self.animation_settings.loops = loops;
+                Command::none()
+            }
+            Message::RenderAnimation => self.start_animation(),
             Message::ColourSelected(colour) => {
                 self.set.colour = Some(colour);
-                self.set.cache.clear()
+                self.set.refresh()
+            }
+            Message::ColouringSelected(colouring) => {
+                self.set.colouring = colouring;
+                self.set.refresh()
+            }
+            Message::InteriorColouringSelected(interior_colouring) => {
+                self.set.interior_colouring = interior_colouring;
+                self.set.refresh()
+            }
+            Message::PerturbationToggled(perturbation) => {
+                self.set.perturbation = perturbation;
+                self.set.refresh()
+            }
+            Message::JuliaToggled(julia) => self.set.set_julia_mode(julia),
+            Message::InteriorChecksSet(checks) => {
+                self.set.interior_checks = checks;
+                self.set.refresh()
+            }
+            Message::GuessingToggled(guessing) => {
+                self.set.guessing = guessing;
+                self.set.refresh()
+            }
+            Message::SimdToggled(simd) => {
+                self.set.simd = simd;
+                self.set.refresh()
+            }
+            Message::TileOverlayToggled(overlay) => {
+                self.set.tile_overlay = overlay;
+                self.set.cache.clear();
+                Command::none()
+            }
+            Message::SupersamplingSet(supersampling) => {
+                self.set.supersampling = supersampling;
+                self.set.refresh()
+            }
+            Message::PickJulia(point) => {
+                if self.set.julia.is_some() {
+                    return Command::none();
+                }
+                self.set.julia_c = self.set.complex_at(point);
+                self.set.set_julia_mode(true)
+            }
+            Message::CursorMoved(point) => {
+                if self.set.julia.is_some() {
+                    return Command::none();
+                }
+                let params = self.set.julia_preview_params(self.set.complex_at(point));
+                let colours = self.set.colour.unwrap_or(Colour::Default).to_array();
+                start_preview(
+                    &mut self.preview_job,
+                    params,
+                    colours,
+                    Message::JuliaPreviewRender,
+                )
+            }
+            Message::FractalSelected(fractal) => {
+                self.set.fractal = fractal;
+                self.set.refresh()
+            }
+            Message::PowerSet(power) => {
+                self.set.power = power;
+                self.set.fractal = self.set.fractal.clone().with_power(power);
+                self.set.refresh()
+            }
+            // only check the formula while typing, it gets used once enter is pressed
+            Message::ExpressionEdited(source) => {
+                self.set.expression_error = Expression::parse(&source).err().map(|e| e.to_string());
+                self.set.expression = source;
+                Command::none()
+            }
+            Message::ExpressionSubmitted => match Expression::parse(&self.set.expression) {
+                Ok(expression) => {
+                    let expression = Arc::new(expression);
+                    self.set.custom = Some(expression.clone());
+                    self.set.fractal = Fractal::Custom(expression);
+                    self.set.refresh()
+                }
+                Err(error) => {
+                    self.set.expression_error = Some(error.to_string());
+                    Command::none()
+                }
+            },
+            Message::CanvasRender(id, event) => {
+                self.set.job_event(id, event);
+                Command::none()
+            }
+            Message::JuliaPreviewRender(id, event) => {
+                if let Some(preview) = preview_event(&mut self.preview_job, id, event) {
+                    self.preview = Some(preview)
+                }
+                Command::none()
+            }
+            Message::AnimationPreviewRender(id, event) => {
+                let job = &mut self.animation_settings.preview_job;
+                if let Some(preview) = preview_event(job, id, event) {
+                    self.animation_settings.preview = Some(preview)
+                }
+                Command::none()
+            }
+            // anything from an export that has since been cancelled is ignored
+            Message::ExportRender(id, event) => {
+                if self.export.as_ref().map(|(job, ..)| job.id()) != Some(id) {
+                    return Command::none();
+                }
+                match event {
+                    JobEvent::Progress(progress) => {
+                        self.export_progress = progress;
+                        Command::none()
+                    }
+                    JobEvent::Saved(result) => {
+                        if let Some((_, path)) = self.export.take() {
+                            self.export_status = match result {
+                                Ok(()) => format!("Saved {}", path.display()),
+                                Err(error) => format!("Export failed: {}", error),
+                            };
+                        }
+                        Command::none()
+                    }
+                    JobEvent::Pass(_) | JobEvent::Tiles(_) | JobEvent::Finished(..) => {
+                        Command::none()
+                    }
+                }
+            }
+            Message::CancelExport => {
+                if let Some((export, path)) = self.export.take() {
+                    export.cancel();
+                    // strips that were finished are kept for next time
+                    self.export_status = if strips_folder(&path).exists() || path.is_dir() {
+                        "Export cancelled, exporting this again carries on where it stopped"
+                            .to_string()
+                    } else {
+                        "Export cancelled".to_string()
+                    };
+                }
+                Command::none()
             }
         }
     }
 
+    // escape cancels an export that's taking too long
+    fn subscription(&self) -> Subscription<Message> {
+        keyboard::on_key_press(|key, _modifiers| {
+            (key == keyboard::Key::Named(keyboard::key::Named::Escape))
+                .then_some(Message::CancelExport)
+        })
+    }
+
     fn view(&self) -> Element<'_, Self::Message> {
+        let preview: Element<'_, Self::Message> = match self.set.julia {
+            None => match &self.preview {
+                Some(preview) => image(preview.clone())
+                    .width(PREVIEW_SIZE)
+                    .height(PREVIEW_SIZE)
+                    .into(),
+                None => Space::new(PREVIEW_SIZE, PREVIEW_SIZE).into(),
+            },
+            Some(c) => text(format!("Julia set of c = {}", c)).into(),
+        };
+        let checks = self.set.interior_checks;
+        let supersampling = self.set.supersampling;
+        // progress bars only show up while something is rendering
+        let mut render_progress = column![].spacing(5).padding([0, 10]);
+        if self.set.job.is_some() {
+            render_progress = render_progress.push(row![
+                text("Rendering").width(Length::Fixed(80.0)),
+                progress_bar(0.0..=1.0, self.set.progress).height(Length::Fixed(10.0))
+            ]);
+        }
+        render_progress = render_progress.push(match self.export {
+            Some(_) => Element::from(
+                row![
+                    text("Exporting").width(Length::Fixed(80.0)),
+                    progress_bar(0.0..=1.0, self.export_progress).height(Length::Fixed(10.0)),
+                    button(text("Cancel")).on_press(Message::CancelExport)
+                ]
+                .spacing(10)
+                .align_items(iced::Alignment::Center),
+            ),
+            None => text(&self.export_status).into(),
+        });
+        let formula_options: Element<'_, Self::Message> = match self.set.fractal {
+            Fractal::Multibrot(power) => row![
+                text(format!("Power: {}", power)),
+                slider(2..=8, power, |power| Message::PowerSet(power as f64))
+            ]
+            .padding(10)
+            .spacing(20)
+            .into(),
+            Fractal::RealMultibrot(power) => row![
+                text(format!("Power: {:.2}", power)),
+                slider(1.0..=8.0, power, Message::PowerSet).step(0.05)
+            ]
+            .padding(10)
+            .spacing(20)
+            .into(),
+            Fractal::Custom(_) => row![
+                text("z ="),
+                text_input("z^3 - z + c", &self.set.expression)
+                    .on_input(Message::ExpressionEdited)
+                    .on_submit(Message::ExpressionSubmitted),
+                text(self.set.expression_error.as_deref().unwrap_or(""))
+                    .style(iced::Color::from_rgb(0.8, 0.0, 0.0))
+            ]
+            .padding(10)
+            .spacing(20)
+            .align_items(iced::Alignment::Center)
+            .into(),
+            _ => column![].into(),
+        };
+        let export_panel = self.export_panel();
+        let animation_panel = self.animation_panel();
         column![
-            canvas::Canvas::new(&self.set)
-                .width(CANVAS_SIZE)
-                .height(CANVAS_SIZE),
+            row![
+                ImageView::new(self.set.image.clone(), &self.set)
+                    .width(CANVAS_SIZE)
+                    .height(CANVAS_SIZE),
+                preview
+            ]
+            .spacing(10),
             row![
                 text(format!("Iterations: {:?}", self.set.max_iterations as u32)),
                 slider(
@@ -141,25 +895,121 @@ impl Sandbox for MandelbrotExplorer {
                     self.set.max_iterations as u32,
                     Message::IterationSet
                 )
-                .width(Length::Fill)
+                .width(Length::Fill),
+                text(format!("Bailout: {}", self.set.bailout)),
+                // in powers of 2, as it only makes a difference when it changes a lot
+                slider(
+                    1..=20,
+                    self.set.bailout.log2().round() as i32,
+                    Message::BailoutSet
+                )
+                .width(Length::Fixed(150.0)),
+                text(format!("Palette offset: {:.2}", self.set.palette_offset)),
+                slider(
+                    0.0..=1.0,
+                    self.set.palette_offset,
+                    Message::PaletteOffsetSet
+                )
+                .step(0.01)
+                .width(Length::Fixed(150.0))
             ]
             .padding(10)
             .spacing(20),
             text(format!(
-                "Centre: {} + {}i, Zoom: {}",
-                self.set.centre.re(),
-                self.set.centre.im(),
-                1.0 / self.set.resolution
+                "Centre: {}, Zoom: {}",
+                self.set.centre,
+                Decimal::from_f64(1.0, 0).div(&self.set.resolution, self.set.resolution.scale())
             )),
+            text(match &self.set.plane {
+                Some(plane) if self.set.perturbation => format!(
+                    "Series approximation skipped {} iterations",
+                    plane.skipped_iterations()
+                ),
+                _ => String::new(),
+            }),
+            render_progress,
             row![
                 button(text("Refresh Image")).on_press(Message::Refresh),
-                button(text("Render 4000x4000 image")).on_press(Message::RenderImage),
+                button(text("Export image")).on_press(Message::ExportPanelToggled),
+                button(text("Animation")).on_press(Message::AnimationPanelToggled),
                 pick_list(
                     &[Colour::Default, Colour::Rainbow, Colour::GreenBlack][..],
                     self.set.colour,
                     Message::ColourSelected
-                )
+                ),
+                pick_list(
+                    Fractal::all(self.set.power, self.set.custom.as_ref()),
+                    Some(self.set.fractal.clone()),
+                    Message::FractalSelected
+                ),
+                checkbox("Deep zoom", self.set.perturbation)
+                    .on_toggle(Message::PerturbationToggled),
+                checkbox("Julia set", self.set.julia.is_some()).on_toggle(Message::JuliaToggled)
             ]
+            .spacing(10)
+            .align_items(iced::Alignment::Center),
+            row![
+                pick_list(
+                    &Colouring::ALL[..],
+                    Some(self.set.colouring),
+                    Message::ColouringSelected
+                ),
+                pick_list(
+                    &InteriorColouring::ALL[..],
+                    Some(self.set.interior_colouring),
+                    Message::InteriorColouringSelected
+                ),
+                checkbox("Cardioid check", checks.cardioid).on_toggle(move |cardioid| {
+                    Message::InteriorChecksSet(InteriorChecks { cardioid, ..checks })
+                }),
+                checkbox("Bulb check", checks.bulb).on_toggle(move |bulb| {
+                    Message::InteriorChecksSet(InteriorChecks { bulb, ..checks })
+                }),
+                checkbox("Periodicity check", checks.periodicity).on_toggle(move |periodicity| {
+                    Message::InteriorChecksSet(InteriorChecks {
+                        periodicity,
+                        ..checks
+                    })
+                }),
+                checkbox("Guess flat areas", self.set.guessing).on_toggle(Message::GuessingToggled),
+                checkbox("SIMD", self.set.simd).on_toggle(Message::SimdToggled),
+                checkbox("Tile overlay", self.set.tile_overlay)
+                    .on_toggle(Message::TileOverlayToggled)
+            ]
+            .spacing(10)
+            .align_items(iced::Alignment::Center),
+            row![
+                text(format!("Supersampling: {0}x{0}", supersampling.size)),
+                slider(1..=4, supersampling.size, move |size| {
+                    Message::SupersamplingSet(Supersampling {
+                        size,
+                        ..supersampling
+                    })
+                })
+                .width(Length::Fixed(150.0)),
+                pick_list(
+                    &SamplePattern::ALL[..],
+                    Some(supersampling.pattern),
+                    move |pattern| {
+                        Message::SupersamplingSet(Supersampling {
+                            pattern,
+                            ..supersampling
+                        })
+                    }
+                ),
+                checkbox("Only on edges", supersampling.adaptive).on_toggle(move |adaptive| {
+                    Message::SupersamplingSet(Supersampling {
+                        adaptive,
+                        ..supersampling
+                    })
+                })
+            ]
+            .padding([0, 10])
+            .spacing(10)
+            .align_items(iced::Alignment::Center),
+            formula_options,
+            export_panel,
+            animation_panel
         ]
         .width(Length::Fill)
         .align_items(iced::Alignment::Center)
@@ -167,37 +1017,501 @@ impl Sandbox for MandelbrotExplorer {
     }
 }
 
+impl MandelbrotExplorer {
+    // the size, shape and file of the image to export, which only shows up once it's been asked for
+    fn export_panel(&self) -> Element<'_, Message> {
+        let settings = &self.export_settings;
+        if !settings.open {
+            return column![].into();
+        }
+        let size = settings.size();
+        let mut panel = column![row![
+            text("Size:"),
+            text_input("width", &settings.width)
+                .on_input(Message::ExportWidthEdited)
+                .width(Length::Fixed(70.0)),
+            text("x"),
+            text_input("height", &settings.height)
+                .on_input(Message::ExportHeightEdited)
+                .width(Length::Fixed(70.0)),
+            checkbox("Same shape as view", settings.match_view)
+                .on_toggle(Message::ExportMatchViewToggled),
+            text_input("output.png", &settings.path)
+                .on_input(Message::ExportPathEdited)
+                .on_submit(Message::RenderImage),
+            button(text("Browse")).on_press(Message::ChooseExportPath),
+            pick_list(
+                &ExportFormat::ALL[..],
+                Some(settings.format),
+                Message::ExportFormatSelected
+            ),
+            button(text("Export")).on_press_maybe(size.is_ok().then_some(Message::RenderImage))
+        ]
+        .spacing(10)
+        .align_items(iced::Alignment::Center)]
+        .spacing(5)
+        .padding(10);
+        if let Err(error) = size {
+            panel = panel.push(text(error).style(iced::Color::from_rgb(0.8, 0.0, 0.0)));
+        }
+        if settings.confirm_overwrite {
+            panel = panel.push(
+                row![
+                    text(format!("{} already exists, replace it?", settings.path)),
+                    button(text("Replace")).on_press(Message::OverwriteConfirmed),
+                    button(text("Cancel")).on_press(Message::OverwriteCancelled)
+                ]
+                .spacing(10)
+                .align_items(iced::Alignment::Center),
+            );
+        }
+        panel.into()
+    }
+
+    // Keyframes from the current view, a scrubber that previews any frame of the zoom, and where
+    // to render the frames. Only shows up once it's been asked for
+    fn animation_panel(&self) -> Element<'_, Message> {
+        let settings = &self.animation_settings;
+        if !settings.open {
+            return column![].into();
+        }
+        let frames = self.animation.frames();
+        let size = settings.size();
+        let playback = settings.playback();
+        let mut panel = column![row![
+            text("Keyframe at frame"),
+            text_input("0", &settings.frame)
+                .on_input(Message::KeyframeFrameEdited)
+                .on_submit(Message::AddKeyframe)
+                .width(Length::Fixed(70.0)),
+            button(text("Add keyframe")).on_press(Message::AddKeyframe),
+            button(text("Cycle palette")).on_press(Message::CyclePalette),
+            button(text("Clear keyframes")).on_press(Message::ClearKeyframes),
+            text(format!(
+                "{} keyframes, {} frames",
+                self.animation.keyframes().len(),
+                frames
+            ))
+        ]
+        .spacing(10)
+        .align_items(iced::Alignment::Center)]
+        .spacing(5)
+        .padding(10);
+        // a slider needs more than one value to slide between
+        if frames > 1 {
+            let preview: Element<'_, Message> = match &settings.preview {
+                Some(preview) => image(preview.clone())
+                    .width(PREVIEW_SIZE)
+                    .height(PREVIEW_SIZE)
+                    .into(),
+                None => Space::new(PREVIEW_SIZE, PREVIEW_SIZE).into(),
+            };
+            panel = panel.push(
+                row![
+                    text(format!("Frame {}", settings.scrub)),
+                    slider(
+                        0..=(frames - 1) as u32,
+                        settings.scrub,
+                        Message::AnimationScrubbed
+                    ),
+                    button(text("Go to frame")).on_press(Message::GoToFrame),
+                    preview
+                ]
+                .spacing(10)
+                .align_items(iced::Alignment::Center),
+            );
+        }
+        panel = panel.push(
+            row![
+                text("Size:"),
+                text_input("width", &settings.width)
+                    .on_input(Message::AnimationWidthEdited)
+                    .width(Length::Fixed(70.0)),
+                text("x"),
+                text_input("height", &settings.height)
+                    .on_input(Message::AnimationHeightEdited)
+                    .width(Length::Fixed(70.0)),
+                text_input("frames, or a .gif or .png", &settings.folder)
+                    .on_input(Message::AnimationFolderEdited),
+                text("Delay (ms):"),
+                text_input("40", &settings.delay)
+                    .on_input(Message::AnimationDelayEdited)
+                    .width(Length::Fixed(50.0)),
+                text("Loops:"),
+                text_input("0", &settings.loops)
+                    .on_input(Message::AnimationLoopsEdited)
+                    .width(Length::Fixed(50.0)),
+                button(text("Render frames")).on_press_maybe(
+                    (size.is_ok() && playback.is_ok() && frames > 0)
+                        .then_some(Message::RenderAnimation)
+                )
+            ]
+            .spacing(10)
+            .align_items(iced::Alignment::Center),
+        );
+        if let Err(error) = size.and(playback) {
+            panel = panel.push(text(error).style(iced::Color::from_rgb(0.8, 0.0, 0.0)));
+        }
+        panel.into()
+    }
+
+    // start previewing the frame the scrubber is on, small and with few enough iterations
+    // to follow it. Whatever frame was being previewed before stays up until it's done
+    fn animation_preview(&mut self) -> Command<Message> {
+        let Some(view) = self.animation.view_at(self.animation_settings.scrub as u64) else {
+            return Command::none();
+        };
+        let base = RenderParams {
+            supersampling: Supersampling::default(),
+            ..self.set.params(PREVIEW_SIZE as u64, PREVIEW_SIZE as u64)
+        };
+        let params = RenderParams {
+            max_iterations: view.max_iterations.min(PREVIEW_MAX_ITERATIONS),
+            ..view.params(&base)
+        };
+        let colours = self.set.colour.unwrap_or(Colour::Default).to_array();
+        start_preview(
+            &mut self.animation_settings.preview_job,
+            params,
+            colours,
+            Message::AnimationPreviewRender,
+        )
+    }
+
+    // the current view as a keyframe
+    fn keyframe(&self, frame: u64) -> Keyframe {
+        Keyframe {
+            frame,
+            centre: self.set.centre.clone(),
+            zoom: 1.0 / self.set.resolution.to_f64(),
+            max_iterations: self.set.max_iterations,
+            palette_offset: self.set.palette_offset,
+        }
+    }
+
+    // Start rendering every frame of the animation into its folder or file, in place of any export
+    fn start_animation(&mut self) -> Command<Message> {
+        let settings = &self.animation_settings;
+        let ((width, height), playback) = match settings
+            .size()
+            .and_then(|size| settings.playback().map(|playback| (size, playback)))
+        {
+            Ok(settings) => settings,
+            Err(error) => {
+                self.export_status = error;
+                return Command::none();
+            }
+        };
+        let colour = self.set.colour.unwrap_or(Colour::Default).to_array();
+        if let Some((export, ..)) = self.export.take() {
+            export.cancel()
+        }
+        let folder = PathBuf::from(&self.animation_settings.folder);
+        let (job, events) = RenderJob::animate(
+            self.animation.clone(),
+            self.set.params(width, height),
+            colour,
+            folder.clone(),
+            playback,
+        );
+        let id = job.id();
+        self.export = Some((job, folder));
+        self.export_progress = 0.0;
+        self.export_status = String::new();
+        Command::run(events, move |event| Message::ExportRender(id, event))
+    }
+
+    // a typed or chosen path, which picks the format too if it has an extension for one
+    fn set_export_path(&mut self, path: String) {
+        if let Some(format) = ExportFormat::from_path(Path::new(&path)) {
+            self.export_settings.format = format;
+        }
+        self.export_settings.path = path;
+        self.export_settings.confirm_overwrite = false;
+    }
+
+    // Start rendering the current view with the export panel's settings. The image spans the
+    // same width of the plane as the canvas does, whatever size it is
+    fn start_export(&mut self) -> Command<Message> {
+        self.export_settings.confirm_overwrite = false;
+        let (width, height) = match self.export_settings.size() {
+            Ok(size) => size,
+            Err(error) => {
+                self.export_status = error;
+                return Command::none();
+            }
+        };
+        // enough digits for however many times smaller the pixels get
+        let scale = self.set.resolution.scale() + width.to_string().len() as u32 + 5;
+        let params = RenderParams {
+            resolution: self
+                .set
+                .resolution
+                .mul_exact(&Decimal::from_f64(CANVAS_SIZE as f64, 0))
+                .div(&Decimal::from_f64(width as f64, 0), scale)
+                .normalised(),
+            ..self.set.params(width, height)
+        };
+        let colour = self.set.colour.unwrap_or(Colour::Default).to_array();
+        if let Some((export, ..)) = self.export.take() {
+            export.cancel()
+        }
+        let path = PathBuf::from(&self.export_settings.path);
+        let (job, command) = match self.export_settings.format.image_format() {
+            Some(format) => {
+                let (job, events) = RenderJob::export(params, colour, path.clone(), format);
+                let id = job.id();
+                (
+                    job,
+                    Command::run(events, move |event| Message::ExportRender(id, event)),
+                )
+            }
+            None => {
+                let (job, events) = RenderJob::export_data(params, path.clone());
+                let id = job.id();
+                (
+                    job,
+                    Command::run(events, move |event| Message::ExportRender(id, event)),
+                )
+            }
+        };
+        self.export = Some((job, path));
+        self.export_progress = 0.0;
+        self.export_status = String::new();
+        command
+    }
+}
+
 #[derive(Default, Debug)]
 struct MandelbrotSet {
     max_iterations: u64,
-    centre: Complex<f64>,
-    resolution: f64,
+    bailout: f64,
+    centre: ComplexDecimal,
+    resolution: Decimal,
     colour: Option<Colour>,
+    // how far round the palette the colours start, from 0 to 1
+    palette_offset: f64,
+    colouring: Colouring,
+    interior_colouring: InteriorColouring,
+    fractal: Fractal,
+    // power of the multibrot fractals
+    power: f64,
+    // the formula being typed in, and the last one that compiled
+    expression: String,
+    expression_error: Option<String>,
+    custom: Option<Arc<Expression>>,
+    perturbation: bool,
+    interior_checks: InteriorChecks,
+    guessing: bool,
+    simd: bool,
+    supersampling: Supersampling,
+    // the c of the julia set being shown, or None when showing the mandelbrot set
+    julia: Option<Complex<f64>>,
+    julia_c: Complex<f64>,
+    // centre and resolution of whichever of the mandelbrot/julia views isn't being shown
+    other_view: (ComplexDecimal, Decimal),
+    plane: Option<MandelbrotPlane<Fractal>>,
+    image: Option<image::Handle>,
+    frames: VecDeque<Frame>,
+    // the render of the current view if it isn't finished yet, along with what it's rendering
+    job: Option<(RenderJob, RenderParams<Fractal>, Colour)>,
+    progress: f32,
+    // the iteration data of views rendered before, in tiles that can be reused by later views
+    tiles: Arc<Mutex<TileCache<Fractal>>>,
+    // which tiles the current view came from, if it came from tiles
+    rendered_tiles: Option<Vec<RenderedTile>>,
+    tile_overlay: bool,
     cache: canvas::Cache,
 }
 
+// A view that has already been rendered
+#[derive(Debug)]
+struct Frame {
+    params: RenderParams<Fractal>,
+    colour: Colour,
+    plane: MandelbrotPlane<Fractal>,
+    image: image::Handle,
+}
+
 impl MandelbrotSet {
     fn new(size: u16) -> MandelbrotSet {
         MandelbrotSet {
             max_iterations: 1000,
-            centre: Complex::new(0.0, 0.0),
-            resolution: 4.0 / size as f64,
+            bailout: DEFAULT_BAILOUT,
+            centre: ComplexDecimal::default(),
+            resolution: Decimal::from_f64(4.0 / size as f64, 0),
             colour: Some(Colour::Default),
+            power: 3.0,
+            expression: DEFAULT_EXPRESSION.to_string(),
+            custom: Expression::parse(DEFAULT_EXPRESSION).ok().map(Arc::new),
+            julia_c: Complex::new(-0.8, 0.156),
+            interior_checks: InteriorChecks::ALL,
+            simd: true,
+            other_view: (
+                ComplexDecimal::default(),
+                Decimal::from_f64(4.0 / size as f64, 0),
+            ),
             ..Default::default()
         }
     }
+
+    // switch between the mandelbrot set and the julia set of julia_c,
+    // each keeps its own view so switching back ends up where it was left
+    fn set_julia_mode(&mut self, julia: bool) -> Command<Message> {
+        if julia == self.julia.is_some() {
+            return Command::none();
+        }
+        let view = (self.centre.clone(), self.resolution.clone());
+        (self.centre, self.resolution) = std::mem::replace(&mut self.other_view, view);
+        self.julia = julia.then_some(self.julia_c);
+        self.refresh()
+    }
+
+    // the (low precision) complex number under a point on the canvas
+    fn complex_at(&self, point: Point) -> Complex<f64> {
+        let resolution = self.resolution.to_f64();
+        self.centre.to_f64()
+            + Complex::new(
+                (point.x as f64 - (CANVAS_SIZE as f64 / 2.0)) * resolution,
+                (point.y as f64 - (CANVAS_SIZE as f64 / 2.0)) * resolution,
+            )
+    }
+
+    // start rendering the current view on another thread, cancelling whatever was being
+    // rendered before. One of the last few views gets shown again straight away instead
+    fn refresh(&mut self) -> Command<Message> {
+        if let Some((job, ..)) = self.job.take() {
+            job.cancel()
+        }
+        self.rendered_tiles = None;
+        self.cache.clear();
+        let params = self.params(CANVAS_SIZE as u64, CANVAS_SIZE as u64);
+        let colour = self.colour.unwrap_or(Colour::Default);
+        let cached = self
+            .frames
+            .iter()
+            .position(|frame| frame.params == params && frame.colour == colour)
+            .and_then(|index| self.frames.remove(index));
+        if let Some(frame) = cached {
+            self.show(frame);
+            return Command::none();
+        }
+        let (job, events) = RenderJob::start(
+            params.clone(),
+            colour.to_array(),
+            true,
+            Some(self.tiles.clone()),
+        );
+        let id = job.id();
+        self.job = Some((job, params, colour));
+        self.progress = 0.0;
+        Command::run(events, move |event| Message::CanvasRender(id, event))
+    }
+
+    // keep track of the render of the current view, anything from an older one is ignored
+    fn job_event(&mut self, id: u64, event: JobEvent<Fractal>) {
+        if self.job.as_ref().map(|(job, ..)| job.id()) != Some(id) {
+            return;
+        }
+        match event {
+            JobEvent::Progress(progress) => self.progress = progress,
+            JobEvent::Pass(buffer) => self.image = Some(image_handle(&buffer)),
+            JobEvent::Tiles(tiles) => {
+                self.rendered_tiles = Some(tiles);
+                self.cache.clear()
+            }
+            JobEvent::Saved(_) => {}
+            JobEvent::Finished(plane, buffer) => {
+                if let Some((_, params, colour)) = self.job.take() {
+                    self.show(Frame {
+                        params,
+                        colour,
+                        plane,
+                        image: image_handle(&buffer),
+                    })
+                }
+            }
+        }
+    }
+
+    // put a finished render on the canvas, and remember it for later
+    fn show(&mut self, frame: Frame) {
+        self.plane = Some(frame.plane.clone());
+        self.image = Some(frame.image.clone());
+        self.frames.push_back(frame);
+        if self.frames.len() > FRAME_CACHE_SIZE {
+            self.frames.pop_front();
+        }
+        self.cache.clear()
+    }
+
+    // the julia set of c with the current formula, small enough to be quick
+    fn julia_preview_params(&self, c: Complex<f64>) -> RenderParams<Fractal> {
+        RenderParams {
+            resolution: Decimal::from_f64(4.0 / PREVIEW_SIZE as f64, 0),
+            max_iterations: self.max_iterations.min(PREVIEW_MAX_ITERATIONS),
+            width: PREVIEW_SIZE as u64,
+            height: PREVIEW_SIZE as u64,
+            julia: Some(c),
+            formula: self.fractal.clone(),
+            interior_checks: InteriorChecks::ALL,
+            ..Default::default()
+        }
+    }
+
+    // the current view, rendered at a given size
+    fn params(&self, width: u64, height: u64) -> RenderParams<Fractal> {
+        RenderParams {
+            centre: self.centre.clone(),
+            resolution: self.resolution.clone(),
+            max_iterations: self.max_iterations,
+            width,
+            height,
+            projection: Projection::Flat,
+            perturbation: self.perturbation,
+            julia: self.julia,
+            formula: self.fractal.clone(),
+            interior_checks: self.interior_checks,
+            colouring: self.colouring,
+            interior_colouring: self.interior_colouring,
+            bailout: self.bailout,
+            palette_offset: self.palette_offset,
+            guessing: self.guessing,
+            simd: self.simd,
+            supersampling: self.supersampling,
+        }
+    }
+
+    // move the centre of the view to a point on the canvas
+    fn move_centre(&mut self, point: Point) {
+        let offset = |pixel: f32| {
+            format!("{}", pixel - CANVAS_SIZE as f32 / 2.0)
+                .parse::<Decimal>()
+                .unwrap_or_default()
+                .mul_exact(&self.resolution)
+        };
+        self.centre = ComplexDecimal::new(
+            (&self.centre.re + &offset(point.x)).normalised(),
+            (&self.centre.im + &offset(point.y)).normalised(),
+        );
+    }
 }
 
 impl canvas::Program<Message> for MandelbrotSet {
-    type State = ();
+    type State = keyboard::Modifiers;
 
     fn update(
         &self,
-        _state: &mut Self::State,
+        state: &mut Self::State,
         event: Event,
         bounds: Rectangle,
         cursor: Cursor,
     ) -> (Status, Option<Message>) {
+        // remember shift for shift+click picking julia sets
+        if let Event::Keyboard(keyboard::Event::ModifiersChanged(modifiers)) = event {
+            *state = modifiers;
+            return (Status::Ignored, None);
+        }
         let Some(cursor_position) = cursor.position_in(bounds) else {
             return (Status::Ignored, None);
         };
@@ -205,6 +1519,14 @@ impl canvas::Program<Message> for MandelbrotSet {
         match event {
             Event::Mouse(mouse_event) => {
                 let message = match mouse_event {
+                    iced::mouse::Event::ButtonPressed(iced::mouse::Button::Left)
+                        if state.shift() =>
+                    {
+                        Some(Message::PickJulia(cursor_position))
+                    }
+                    iced::mouse::Event::ButtonPressed(iced::mouse::Button::Middle) => {
+                        Some(Message::PickJulia(cursor_position))
+                    }
                     iced::mouse::Event::ButtonPressed(iced::mouse::Button::Left) => {
                         Some(Message::ZoomIn(cursor_position))
                     }
@@ -231,6 +1553,9 @@ impl canvas::Program<Message> for MandelbrotSet {
                             } // don't react to horizontal scrolling (yet)
                         }
                     },
+                    iced::mouse::Event::CursorMoved { .. } => {
+                        Some(Message::CursorMoved(cursor_position))
+                    }
                     _ => None,
                 };
                 (Status::Captured, message)
@@ -247,38 +1572,158 @@ impl canvas::Program<Message> for MandelbrotSet {
         bounds: Rectangle,
         _cursor: Cursor,
     ) -> Vec<canvas::Geometry> {
+        // the set itself is drawn underneath as an image, this only draws over it
         let geom = self.cache.draw(renderer, bounds.size(), |frame| {
             frame.stroke(
                 &canvas::Path::rectangle(Point::ORIGIN, frame.size()),
                 canvas::Stroke::default(),
             );
-            let points = mandelbrot_from_params_parallel(
-                self.centre,
-                self.resolution,
-                self.max_iterations,
-                frame.width().round() as u64,
-                frame.height().round() as u64,
-                self.colour.unwrap_or(Colour::Default).to_array(),
-            );
-            for point in points {
-                let path = canvas::Path::rectangle(
-                    Point::new(point.0 .0 as f32, point.0 .1 as f32),
-                    Size::new(1.0, 1.0),
-                );
-                frame.stroke(
-                    &path,
-                    canvas::Stroke {
-                        style: canvas::Style::Solid(iced::Color {
-                            r: point.1 .0 as f32 / 255.0,
-                            g: point.1 .1 as f32 / 255.0,
-                            b: point.1 .2 as f32 / 255.0,
-                            a: 1.0,
-                        }),
-                        ..Default::default()
-                    },
-                )
+            if let (true, Some(tiles)) = (self.tile_overlay, &self.rendered_tiles) {
+                self.draw_tile_overlay(frame, tiles);
             }
         });
         vec![geom]
     }
 }
+
+impl MandelbrotSet {
+    // outline the tiles the view came from, green for ones that came out of the cache
+    // and red for ones that had to be rendered, with how the cache is doing in the corner
+    fn draw_tile_overlay(&self, frame: &mut canvas::Frame, tiles: &[RenderedTile]) {
+        let size = iced::Size::new(TILE_SIZE as f32, TILE_SIZE as f32);
+        for tile in tiles {
+            let colour = if tile.cached {
+                iced::Color::from_rgba(0.0, 1.0, 0.0, 0.6)
+            } else {
+                iced::Color::from_rgba(1.0, 0.0, 0.0, 0.6)
+            };
+            frame.stroke(
+                &canvas::Path::rectangle(Point::new(tile.x as f32, tile.y as f32), size),
+                canvas::Stroke::default().with_color(colour),
+            );
+        }
+        let cached = tiles.iter().filter(|tile| tile.cached).count();
+        let cache = self
+            .tiles
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stats = format!(
+            "This view: {} cached, {} rendered\nAll views: {} hits, {} misses\n{}/{} tiles in cache",
+            cached,
+            tiles.len() - cached,
+            cache.hits(),
+            cache.misses(),
+            cache.len(),
+            TILE_CACHE_SIZE
+        );
+        frame.fill_rectangle(
+            Point::new(4.0, 4.0),
+            iced::Size::new(230.0, 56.0),
+            iced::Color::from_rgba(0.0, 0.0, 0.0, 0.6),
+        );
+        frame.fill_text(canvas::Text {
+            content: stats,
+            position: Point::new(8.0, 8.0),
+            color: iced::Color::WHITE,
+            size: 14.0.into(),
+            ..canvas::Text::default()
+        });
+    }
+}
+
+// upload a rendered image to the gpu in one go
+fn image_handle(buffer: &PixelBuffer) -> image::Handle {
+    image::Handle::from_pixels(buffer.width(), buffer.height(), buffer.to_rgba())
+}
+
+// Render a preview on another thread, cancelling the one before it so they can't pile up
+// behind each other while the cursor or the scrubber moves
+fn start_preview(
+    job: &mut Option<RenderJob>,
+    params: RenderParams<Fractal>,
+    colours: &'static [(u8, u8, u8)],
+    message: fn(u64, JobEvent<Fractal>) -> Message,
+) -> Command<Message> {
+    if let Some(job) = job.take() {
+        job.cancel()
+    }
+    let (preview, events) = RenderJob::start(params, colours, false, None);
+    let id = preview.id();
+    *job = Some(preview);
+    Command::run(events, move |event| message(id, event))
+}
+
+// the finished image of a preview, if it's from the latest one
+fn preview_event(
+    job: &mut Option<RenderJob>,
+    id: u64,
+    event: JobEvent<Fractal>,
+) -> Option<image::Handle> {
+    if job.as_ref().map(RenderJob::id) != Some(id) {
+        return None;
+    }
+    match event {
+        JobEvent::Finished(_, buffer) => {
+            *job = None;
+            Some(image_handle(&buffer))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{plane_from_params, render_from_params_parallel};
+
+    #[test]
+    fn zooms_in_and_out() {
+        let (mut explorer, _) = MandelbrotExplorer::new(());
+        let start = explorer.set.resolution.to_f64();
+        assert_eq!(start, 4.0 / CANVAS_SIZE as f64);
+        let _ = explorer.view();
+        let middle = Point::new(CANVAS_SIZE as f32 / 2.0, CANVAS_SIZE as f32 / 2.0);
+        let _ = explorer.update(Message::ZoomIn(middle));
+        let _ = explorer.update(Message::ZoomIn(middle));
+        assert_eq!(explorer.set.resolution.to_f64(), start / 4.0);
+        let _ = explorer.update(Message::ZoomOut(middle));
+        assert_eq!(explorer.set.resolution.to_f64(), start / 2.0);
+        let _ = explorer.view();
+    }
+
+    #[test]
+    fn julia_preview_isnt_flat() {
+        let set = MandelbrotSet::new(CANVAS_SIZE);
+        let params = set.julia_preview_params(Complex::new(-0.8, 0.156));
+        assert_eq!(params.resolution.to_f64(), 4.0 / PREVIEW_SIZE as f64);
+        let preview = render_from_params_parallel(&params, Colour::Default.to_array());
+        let colours: std::collections::HashSet<&[u8]> = preview.pixels().chunks(3).collect();
+        assert!(colours.len() > 100, "only {} colours", colours.len());
+    }
+
+    // moving the cursor again cancels the preview it was over, and that one finishing
+    // late doesn't replace the newer one
+    #[test]
+    fn old_julia_previews_are_dropped() {
+        let (mut explorer, _) = MandelbrotExplorer::new(());
+        let preview_id =
+            |explorer: &MandelbrotExplorer| explorer.preview_job.as_ref().unwrap().id();
+        let _ = explorer.update(Message::CursorMoved(Point::new(100.0, 100.0)));
+        let first = preview_id(&explorer);
+        let _ = explorer.update(Message::CursorMoved(Point::new(200.0, 100.0)));
+        let second = preview_id(&explorer);
+        assert_ne!(first, second);
+        let params = explorer.set.julia_preview_params(Complex::new(-0.8, 0.156));
+        let finished = || {
+            JobEvent::Finished(
+                plane_from_params(&params),
+                render_from_params_parallel(&params, Colour::Default.to_array()),
+            )
+        };
+        let _ = explorer.update(Message::JuliaPreviewRender(first, finished()));
+        assert!(explorer.preview.is_none());
+        let _ = explorer.update(Message::JuliaPreviewRender(second, finished()));
+        assert!(explorer.preview.is_some());
+        assert!(explorer.preview_job.is_none());
+    }
+}
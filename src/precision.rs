@@ -0,0 +1,292 @@
+use num::bigint::BigInt;
+use num::{Signed, Zero};
+use std::ops::{Add, Mul, Sub};
+use std::str::FromStr;
+
+const LOG10_2: f64 = std::f64::consts::LOG10_2;
+
+// the biggest exponent a parsed number can have either way. Far past anything an f64 pixel size
+// can reach, but small enough that the number's digits don't take up all the memory
+const MAX_EXPONENT: i64 = 10_000;
+
+// A fixed point decimal number (mantissa * 10^-scale), used for the few calculations that
+// need more digits than an f64 has, like the reference orbit for perturbation rendering
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Decimal {
+    mantissa: BigInt,
+    scale: u32,
+}
+
+fn pow10(exponent: u32) -> BigInt {
+    BigInt::from(10).pow(exponent)
+}
+
+impl Decimal {
+    pub fn zero(scale: u32) -> Decimal {
+        Decimal {
+            mantissa: BigInt::zero(),
+            scale,
+        }
+    }
+
+    // Goes through the shortest decimal representation of the float, so 0.1 becomes 0.1 and
+    // not 0.1000000000000000055511151231257827... The result has at least scale digits after
+    // the decimal point, and more if the float needs them, so small values never truncate to 0
+    pub fn from_f64(value: f64, scale: u32) -> Decimal {
+        let decimal = format!("{:e}", value)
+            .parse::<Decimal>()
+            .unwrap_or(Decimal::zero(scale));
+        decimal.with_scale(scale.max(decimal.scale))
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        // only the leading digits survive the conversion, so drop the rest before formatting
+        let excess = ((self.mantissa.bits() as f64 * LOG10_2) as u32).saturating_sub(20);
+        let mantissa = &self.mantissa / pow10(excess);
+        format!("{}e{}", mantissa, excess as i64 - self.scale as i64)
+            .parse()
+            .unwrap_or(0.0)
+    }
+
+    // number of digits after the decimal point
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    // rescale to a different number of digits after the decimal point, truncating any extra
+    pub fn with_scale(&self, scale: u32) -> Decimal {
+        let mantissa = if scale >= self.scale {
+            &self.mantissa * pow10(scale - self.scale)
+        } else {
+            &self.mantissa / pow10(self.scale - scale)
+        };
+        Decimal { mantissa, scale }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.mantissa.is_negative()
+    }
+
+    // multiply without truncating anything, so the scale of the result is the sum of both scales
+    pub fn mul_exact(&self, other: &Decimal) -> Decimal {
+        Decimal {
+            mantissa: &self.mantissa * &other.mantissa,
+            scale: self.scale + other.scale,
+        }
+    }
+
+    // divide, keeping scale digits after the decimal point
+    pub fn div(&self, other: &Decimal, scale: u32) -> Decimal {
+        // (a / 10^sa) / (b / 10^sb) = a * 10^(scale + sb - sa) / b / 10^scale
+        let shift = scale as i64 + other.scale as i64 - self.scale as i64;
+        let numerator = if shift >= 0 {
+            &self.mantissa * pow10(shift as u32)
+        } else {
+            &self.mantissa / pow10((-shift) as u32)
+        };
+        Decimal {
+            mantissa: numerator / &other.mantissa,
+            scale,
+        }
+    }
+
+    // remove trailing zeros after the decimal point, which doesn't change the value
+    pub fn normalised(&self) -> Decimal {
+        let mut normalised = self.clone();
+        let ten = BigInt::from(10);
+        while normalised.scale > 0 && (&normalised.mantissa % &ten).is_zero() {
+            normalised.mantissa /= &ten;
+            normalised.scale -= 1;
+        }
+        normalised
+    }
+}
+
+impl Add for &Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: &Decimal) -> Decimal {
+        let scale = self.scale.max(other.scale);
+        Decimal {
+            mantissa: self.with_scale(scale).mantissa + other.with_scale(scale).mantissa,
+            scale,
+        }
+    }
+}
+
+impl Sub for &Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: &Decimal) -> Decimal {
+        let scale = self.scale.max(other.scale);
+        Decimal {
+            mantissa: self.with_scale(scale).mantissa - other.with_scale(scale).mantissa,
+            scale,
+        }
+    }
+}
+
+impl Mul for &Decimal {
+    type Output = Decimal;
+
+    // keeps the larger of the two scales, the extra digits of the product are truncated
+    fn mul(self, other: &Decimal) -> Decimal {
+        Decimal {
+            mantissa: (&self.mantissa * &other.mantissa) / pow10(self.scale.min(other.scale)),
+            scale: self.scale.max(other.scale),
+        }
+    }
+}
+
+// prints every digit, so parsing the output gives back exactly the same number
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let normalised = self.normalised();
+        let digits = normalised.mantissa.abs().to_string();
+        let scale = normalised.scale as usize;
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        let sign = if normalised.is_negative() { "-" } else { "" };
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            write!(f, "{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+// A point on the complex plane with both parts stored as decimals
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ComplexDecimal {
+    pub re: Decimal,
+    pub im: Decimal,
+}
+
+impl ComplexDecimal {
+    pub fn new(re: Decimal, im: Decimal) -> ComplexDecimal {
+        ComplexDecimal { re, im }
+    }
+
+    pub fn to_f64(&self) -> num::Complex<f64> {
+        num::Complex::new(self.re.to_f64(), self.im.to_f64())
+    }
+
+    pub fn with_scale(&self, scale: u32) -> ComplexDecimal {
+        ComplexDecimal::new(self.re.with_scale(scale), self.im.with_scale(scale))
+    }
+}
+
+impl std::fmt::Display for ComplexDecimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.im.is_negative() {
+            let im = &Decimal::zero(0) - &self.im;
+            write!(f, "{} - {}i", self.re, im)
+        } else {
+            write!(f, "{} + {}i", self.re, self.im)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDecimalError;
+
+impl std::fmt::Display for ParseDecimalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid decimal number")
+    }
+}
+
+impl std::error::Error for ParseDecimalError {}
+
+impl FromStr for Decimal {
+    type Err = ParseDecimalError;
+
+    // accepts things like "-1.25", ".5" and "3.2e-40"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, exponent) = match s.find(['e', 'E']) {
+            Some(index) => (
+                &s[..index],
+                s[index + 1..]
+                    .parse::<i64>()
+                    .map_err(|_| ParseDecimalError)?,
+            ),
+            None => (s, 0),
+        };
+        if exponent.abs() > MAX_EXPONENT {
+            return Err(ParseDecimalError);
+        }
+        let (negative, number) = match number.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, number.strip_prefix('+').unwrap_or(number)),
+        };
+        let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(ParseDecimalError);
+        }
+
+        let mut mantissa =
+            BigInt::from_str(&format!("0{}{}", whole, fraction)).map_err(|_| ParseDecimalError)?;
+        if negative {
+            mantissa = -mantissa
+        }
+        let scale = fraction.len() as i64 - exponent;
+        let digits = u32::try_from(scale.abs()).map_err(|_| ParseDecimalError)?;
+        Ok(if scale >= 0 {
+            Decimal {
+                mantissa,
+                scale: digits,
+            }
+        } else {
+            Decimal {
+                mantissa: mantissa * pow10(digits),
+                scale: 0,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_f64_keeps_small_values() {
+        for value in [
+            0.5,
+            0.008,
+            0.1,
+            4.0 / 700.0,
+            2_f64.powi(-53),
+            f64::MIN_POSITIVE,
+        ] {
+            for scale in [0, 5, 400] {
+                let decimal = Decimal::from_f64(value, scale);
+                assert!(decimal.scale() >= scale);
+                assert_eq!(decimal.to_f64(), value, "{} at scale {}", value, scale);
+            }
+        }
+        assert_eq!(Decimal::from_f64(0.1, 0).to_string(), "0.1");
+        assert_eq!(Decimal::from_f64(-2.0, 3).to_string(), "-2");
+    }
+
+    #[test]
+    fn parses() {
+        let parse = |s: &str| s.parse::<Decimal>().map(|decimal| decimal.to_string());
+        assert_eq!(parse("-1.25"), Ok("-1.25".to_string()));
+        assert_eq!(parse(".5"), Ok("0.5".to_string()));
+        assert_eq!(parse("3.2e-5"), Ok("0.000032".to_string()));
+        assert_eq!(parse("1.5E3"), Ok("1500".to_string()));
+        assert_eq!(parse("1e-10000").map(|s| s.len()), Ok(10_002));
+        for bad in ["", ".", "-", "1..2", "1e", "abc", "1e-10001", "1e999999999"] {
+            assert_eq!(parse(bad), Err(ParseDecimalError), "{:?}", bad);
+        }
+        assert_eq!(parse("1e-4294967297"), Err(ParseDecimalError));
+        assert_eq!(parse("1e9223372036854775807"), Err(ParseDecimalError));
+    }
+}
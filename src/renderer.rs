@@ -1,113 +1,566 @@
-use crate::backend::MandelbrotPlane;
+use crate::backend::{
+    Algorithm, Colouring, Formula, InteriorChecks, Mandelbrot, MandelbrotPlane, Projection,
+    ReferenceOrbit, DEFAULT_BAILOUT,
+};
+use crate::colours::InteriorColouring;
+use crate::precision::{ComplexDecimal, Decimal};
+use crate::simd::Kernel;
+use crate::supersampling::Supersampling;
 use num::complex::ComplexFloat;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
-type RawMandelbrotColours = Vec<((u64, u64), (u8, u8, u8))>;
+// An image as one row-major list of rgb bytes, so pixel (x, y) starts at 3 * (y * width + x).
+// This is laid out the same as an image::RgbImage, so it can be saved without copying
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PixelBuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl PixelBuffer {
+    // a black image
+    pub fn new(width: u32, height: u32) -> PixelBuffer {
+        PixelBuffer {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * 3],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> (u8, u8, u8) {
+        let i = self.index(x, y);
+        (self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, (r, g, b): (u8, u8, u8)) {
+        let i = self.index(x, y);
+        self.pixels[i..i + 3].copy_from_slice(&[r, g, b]);
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * 3
+    }
+
+    // the same image with an alpha channel, which is what gets uploaded to the gpu
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.pixels
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect()
+    }
+
+    pub fn into_image(self) -> image::RgbImage {
+        // the buffer is always exactly the right size, so this can't fail
+        image::RgbImage::from_raw(self.width, self.height, self.pixels).unwrap_or_default()
+    }
+}
 
-// plot all points from a MandelbrotPlane to xy coordinates on an image and rgb colouring
-pub fn mandelbrot_xy_coordinates_with_colours(
-    set: MandelbrotPlane,
+// colour every pixel of a plane straight into an image,
+// with the real axis going across and the imaginary axis going down
+pub fn render_plane<F: Formula>(set: &MandelbrotPlane<F>, colours: &[(u8, u8, u8)]) -> PixelBuffer {
+    let mut buffer = PixelBuffer::new(set.width() as u32, set.height() as u32);
+    let row_length = set.width() as usize * 3;
+    if row_length > 0 {
+        buffer
+            .pixels
+            .chunks_mut(row_length)
+            .enumerate()
+            .for_each(|(y, row)| render_row(set, colours, y as u64, row));
+    }
+    buffer
+}
+
+// render a plane but with each row in parallel
+pub fn render_plane_parallel<F: Formula>(
+    set: &MandelbrotPlane<F>,
     colours: &[(u8, u8, u8)],
-) -> RawMandelbrotColours {
-    let points = set.points_with_colours(colours);
-    points
-        .into_iter()
-        .map(|(point, colour)| {
-            (
-                (
-                    ((point.point().re() - set.re_min())
-                        / ((set.re_max() - set.re_min()) / (set.width() as f64)))
-                        .round() as u64,
-                    ((point.point().im() - set.im_min())
-                        / ((set.im_max() - set.im_min()) / (set.height() as f64)))
-                        .round() as u64,
-                ),
-                colour,
-            )
-        })
-        .collect()
+) -> PixelBuffer {
+    let mut buffer = PixelBuffer::new(set.width() as u32, set.height() as u32);
+    let row_length = set.width() as usize * 3;
+    if row_length > 0 {
+        buffer
+            .pixels
+            .par_chunks_mut(row_length)
+            .enumerate()
+            .for_each(|(y, row)| render_row(set, colours, y as u64, row));
+    }
+    buffer
 }
 
-// plot points but in parallel
-pub fn mandelbrot_xy_coordinates_with_colours_parallel(
-    set: MandelbrotPlane,
+// how much coarser than the final image the first pass of a progressive render is
+pub const PROGRESSIVE_STEP: u32 = 8;
+
+// Render a plane in parallel, in passes that each double the resolution of the one before.
+// The first pass only colours every step-th pixel along both axes and fills in the block
+// around each one, the next pass then does the pixels halfway between those and so on,
+// so no pixel is worked out twice. on_pass gets to see the image after every pass but the
+// last, and progress is called with how many pixels are done after each row of blocks.
+// A step of 1 renders everything in one go. Gives up and returns None once cancelled is set
+pub fn render_plane_in_passes<F: Formula>(
+    set: &MandelbrotPlane<F>,
     colours: &[(u8, u8, u8)],
-) -> RawMandelbrotColours {
-    let points = set.points_with_colours_parallel(colours);
-    points
-        .into_iter()
-        .map(|(point, colour)| {
-            (
-                (
-                    ((point.point().re() - set.re_min())
-                        / ((set.re_max() - set.re_min()) / (set.width() as f64)))
-                        .round() as u64,
-                    ((point.point().im() - set.im_min())
-                        / ((set.im_max() - set.im_min()) / (set.height() as f64)))
-                        .round() as u64,
-                ),
-                colour,
-            )
-        })
-        .collect()
+    first_step: u32,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+    mut on_pass: impl FnMut(&PixelBuffer),
+) -> Option<PixelBuffer> {
+    let mut buffer = PixelBuffer::new(set.width() as u32, set.height() as u32);
+    let row_length = set.width() as usize * 3;
+    if row_length == 0 {
+        return Some(buffer);
+    }
+    let pixels_done = AtomicU64::new(0);
+    let mut step = first_step.max(1).next_power_of_two() as usize;
+    loop {
+        let first_pass = step as u32 >= first_step;
+        buffer
+            .pixels
+            .par_chunks_mut(row_length * step)
+            .enumerate()
+            .for_each(|(block_row, rows)| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return;
+                }
+                let y = block_row * step;
+                // the previous pass already did every other pixel on every other row
+                let xs: Vec<usize> = (0..set.width() as usize)
+                    .step_by(step)
+                    .filter(|x| {
+                        first_pass || !x.is_multiple_of(2 * step) || !y.is_multiple_of(2 * step)
+                    })
+                    .collect();
+                let points: Vec<_> = xs
+                    .iter()
+                    .map(|&x| set.point_at(x as u64, y as u64))
+                    .collect();
+                for (&x, (r, g, b)) in xs.iter().zip(set.colours(&points, colours)) {
+                    let block_width = step.min(set.width() as usize - x);
+                    for row in rows.chunks_exact_mut(row_length) {
+                        for pixel in row[x * 3..(x + block_width) * 3].chunks_exact_mut(3) {
+                            pixel.copy_from_slice(&[r, g, b]);
+                        }
+                    }
+                }
+                let done = xs.len() as u64;
+                progress(pixels_done.fetch_add(done, Ordering::Relaxed) + done);
+            });
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        if step == 1 {
+            break;
+        }
+        on_pass(&buffer);
+        step /= 2;
+    }
+    Some(buffer)
 }
 
-// instead of using a MandelbrotPlane,
-// use simpler parameters which then get converted into a MandelbrotPlane
-pub fn mandelbrot_xy_coords_from_params(
-    centre: num::Complex<f64>,
-    resolution: f64,
-    max_iterations: u64,
-    width: u64,
-    height: u64,
+// size of the squares that get guessed separately, and so in parallel
+const GUESS_TILE_SIZE: usize = 32;
+
+// Render a plane by guessing, or Mariani-Silver subdivision. The image is split into tiles and
+// only the border of each one is worked out at first. If none of the border ever escaped, the
+// whole tile is inside the set too, as the set is connected with nothing outside it cut off,
+// so it gets filled in with the interior colouring without iterating it. Otherwise it's split
+// into four and those are checked the same way, down to rectangles too small to be worth it.
+// Borders are compared by iteration count rather than colour, and only the inside of the set
+// is ever filled in, as the smooth colours outside it need every pixel's own orbit anyway.
+// That's also where nearly all the time goes. It can very occasionally miss a filament that
+// slips between the pixels of a border. Progress and cancellation work like they do for
+// render_plane_in_passes, but there are no rough passes along the way
+pub fn render_plane_guessing<F: Formula>(
+    set: &MandelbrotPlane<F>,
     colours: &[(u8, u8, u8)],
-) -> RawMandelbrotColours {
-    let real_width = width as f64 * resolution;
-    let real_height = height as f64 * resolution;
-    let re_max = centre.re() + real_width / 2.0;
-    let re_min = centre.re() - real_width / 2.0;
-    let im_max = centre.im() + real_height / 2.0;
-    let im_min = centre.im() - real_height / 2.0;
-
-    mandelbrot_xy_coordinates_with_colours(
-        MandelbrotPlane::new(
-            re_min,
-            re_max,
-            im_min,
-            im_max,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Option<PixelBuffer> {
+    let mut buffer = PixelBuffer::new(set.width() as u32, set.height() as u32);
+    let width = set.width() as usize;
+    if width == 0 {
+        return Some(buffer);
+    }
+    let pixels_done = AtomicU64::new(0);
+    buffer
+        .pixels
+        .par_chunks_mut(width * 3 * GUESS_TILE_SIZE)
+        .enumerate()
+        .for_each(|(tile_row, rows)| {
+            let y = tile_row * GUESS_TILE_SIZE;
+            let height = rows.len() / (width * 3);
+            let tiles: Vec<Tile> = (0..width)
+                .step_by(GUESS_TILE_SIZE)
+                .collect::<Vec<_>>()
+                .into_par_iter()
+                .map(|x| {
+                    let mut tile = Tile::new(x, y, GUESS_TILE_SIZE.min(width - x), height);
+                    if !cancelled.load(Ordering::Relaxed) {
+                        tile.guess(set, colours, 0, 0, tile.width - 1, tile.height - 1);
+                        let done = (tile.width * tile.height) as u64;
+                        progress(pixels_done.fetch_add(done, Ordering::Relaxed) + done);
+                    }
+                    tile
+                })
+                .collect();
+            for tile in tiles {
+                for (ty, row) in tile.pixels.chunks_exact(tile.width).enumerate() {
+                    let start = (ty * width + tile.x) * 3;
+                    for (pixel, colour) in rows[start..start + tile.width * 3]
+                        .chunks_exact_mut(3)
+                        .zip(row)
+                    {
+                        let (r, g, b) = colour.map(|(_, colour)| colour).unwrap_or_default();
+                        pixel.copy_from_slice(&[r, g, b]);
+                    }
+                }
+            }
+        });
+    if cancelled.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(buffer)
+}
+
+// how many iterations a pixel took along with its colour
+type GuessedPixel = (u64, (u8, u8, u8));
+
+// Part of the image being guessed, with the pixels worked out so far
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    pixels: Vec<Option<GuessedPixel>>,
+}
+
+impl Tile {
+    fn new(x: usize, y: usize, width: usize, height: usize) -> Tile {
+        Tile {
+            x,
+            y,
             width,
             height,
-            max_iterations,
-        ),
-        colours,
-    )
+            pixels: vec![None; width * height],
+        }
+    }
+
+    // how many iterations a pixel in the tile took, colouring it the first time
+    fn iterations<F: Formula>(
+        &mut self,
+        set: &MandelbrotPlane<F>,
+        colours: &[(u8, u8, u8)],
+        x: usize,
+        y: usize,
+    ) -> u64 {
+        let point = set.point_at((self.x + x) as u64, (self.y + y) as u64);
+        self.pixels[y * self.width + x]
+            .get_or_insert_with(|| set.colour_and_iterations(point, colours))
+            .0
+    }
+
+    // fill in the rectangle from (x0, y0) to (x1, y1), including both corners
+    fn guess<F: Formula>(
+        &mut self,
+        set: &MandelbrotPlane<F>,
+        colours: &[(u8, u8, u8)],
+        x0: usize,
+        y0: usize,
+        x1: usize,
+        y1: usize,
+    ) {
+        let max = set.max_iterations();
+        let mut inside = true;
+        let mut same = true;
+        let first = self.iterations(set, colours, x0, y0);
+        for (x, y) in (x0..=x1)
+            .flat_map(|x| [(x, y0), (x, y1)])
+            .chain((y0..=y1).flat_map(|y| [(x0, y), (x1, y)]))
+        {
+            let iterations = self.iterations(set, colours, x, y);
+            inside &= iterations == max;
+            same &= iterations == first;
+        }
+        if inside {
+            for y in y0 + 1..y1 {
+                for x in x0 + 1..x1 {
+                    let point = set.point_at((self.x + x) as u64, (self.y + y) as u64);
+                    self.pixels[y * self.width + x]
+                        .get_or_insert_with(|| (max, set.interior_colour(point)));
+                }
+            }
+        } else if same || x1 - x0 < 4 || y1 - y0 < 4 {
+            // a border that all escaped at once is most likely a band with nothing else in it,
+            // but every pixel in it still has its own colour
+            for y in y0 + 1..y1 {
+                for x in x0 + 1..x1 {
+                    self.iterations(set, colours, x, y);
+                }
+            }
+        } else {
+            // the halves share the line down the middle, so it only gets worked out once
+            let (xm, ym) = ((x0 + x1) / 2, (y0 + y1) / 2);
+            self.guess(set, colours, x0, y0, xm, ym);
+            self.guess(set, colours, xm, y0, x1, ym);
+            self.guess(set, colours, x0, ym, xm, y1);
+            self.guess(set, colours, xm, ym, x1, y1);
+        }
+    }
+}
+
+fn render_row<F: Formula>(
+    set: &MandelbrotPlane<F>,
+    colours: &[(u8, u8, u8)],
+    y: u64,
+    row: &mut [u8],
+) {
+    let points: Vec<_> = (0..set.width()).map(|x| set.point_at(x, y)).collect();
+    for (pixel, (r, g, b)) in row.chunks_exact_mut(3).zip(set.colours(&points, colours)) {
+        pixel.copy_from_slice(&[r, g, b]);
+    }
+}
+
+// The view of the set to render, which gets converted into a MandelbrotPlane
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderParams<F: Formula = Mandelbrot> {
+    pub centre: ComplexDecimal,
+    pub resolution: Decimal,
+    pub max_iterations: u64,
+    pub width: u64,
+    pub height: u64,
+    // with an exponential map, the width and resolution give the view it zooms in from
+    // and the height how far it goes
+    pub projection: Projection,
+    // iterate relative to a high precision reference orbit, for zooms past about 1e15
+    pub perturbation: bool,
+    // render the julia set of this c instead of the mandelbrot set
+    pub julia: Option<num::Complex<f64>>,
+    pub formula: F,
+    // shortcuts for points inside the set, these don't change the result
+    pub interior_checks: InteriorChecks,
+    pub colouring: Colouring,
+    pub interior_colouring: InteriorColouring,
+    // how far z has to get before a point counts as escaped
+    pub bailout: f64,
+    // shifts the colours along the palette, from 0 to 1
+    pub palette_offset: f64,
+    // fill in areas that look flat instead of working out every pixel, see render_plane_guessing
+    pub guessing: bool,
+    // iterate several points at once if the cpu can, this doesn't change the result either
+    pub simd: bool,
+    // more than one sample per pixel, see supersampling::supersample
+    pub supersampling: Supersampling,
+}
+
+impl<F: Formula + Default> Default for RenderParams<F> {
+    fn default() -> Self {
+        RenderParams {
+            centre: ComplexDecimal::default(),
+            resolution: Decimal::default(),
+            max_iterations: 0,
+            width: 0,
+            height: 0,
+            projection: Projection::default(),
+            perturbation: false,
+            julia: None,
+            formula: F::default(),
+            interior_checks: InteriorChecks::default(),
+            colouring: Colouring::default(),
+            interior_colouring: InteriorColouring::default(),
+            bailout: DEFAULT_BAILOUT,
+            palette_offset: 0.0,
+            guessing: false,
+            simd: true,
+            supersampling: Supersampling::default(),
+        }
+    }
+}
+
+// instead of using a MandelbrotPlane,
+// use simpler parameters which then get converted into a MandelbrotPlane
+pub fn render_from_params<F: Formula + Clone>(
+    params: &RenderParams<F>,
+    colours: &[(u8, u8, u8)],
+) -> PixelBuffer {
+    render_plane(&plane_from_params(params), colours)
 }
 
 // render with parameters, but in parallel
-pub fn mandelbrot_from_params_parallel(
-    centre: num::Complex<f64>,
-    resolution: f64,
-    max_iterations: u64,
-    width: u64,
-    height: u64,
+pub fn render_from_params_parallel<F: Formula + Clone>(
+    params: &RenderParams<F>,
     colours: &[(u8, u8, u8)],
-) -> RawMandelbrotColours {
-    let real_width = width as f64 * resolution;
-    let real_height = height as f64 * resolution;
-    let re_max = centre.re() + real_width / 2.0;
-    let re_min = centre.re() - real_width / 2.0;
-    let im_max = centre.im() + real_height / 2.0;
-    let im_min = centre.im() - real_height / 2.0;
-
-    mandelbrot_xy_coordinates_with_colours_parallel(
-        MandelbrotPlane::new(
-            re_min,
-            re_max,
-            im_min,
-            im_max,
-            width,
-            height,
-            max_iterations,
-        ),
-        colours,
+) -> PixelBuffer {
+    render_plane_parallel(&plane_from_params(params), colours)
+}
+
+// with perturbation the plane is built around the centre rather than containing it,
+// as its bounds are offsets from the reference orbit.
+// julia sets and most formulas are always rendered directly, so perturbation is ignored for them
+pub fn plane_from_params<F: Formula + Clone>(params: &RenderParams<F>) -> MandelbrotPlane<F> {
+    let perturbation =
+        params.perturbation && params.julia.is_none() && params.formula.is_mandelbrot();
+    let resolution = params.resolution.to_f64();
+    let real_width = params.width as f64 * resolution;
+    // an exponential map is all inside the square its top row goes round
+    let real_height = match params.projection {
+        Projection::Flat => params.height as f64 * resolution,
+        Projection::Exponential => real_width,
+    };
+    let offset = if perturbation {
+        num::Complex::new(0.0, 0.0)
+    } else {
+        params.centre.to_f64()
+    };
+    let re_max = offset.re() + real_width / 2.0;
+    let re_min = offset.re() - real_width / 2.0;
+    let im_max = offset.im() + real_height / 2.0;
+    let im_min = offset.im() - real_height / 2.0;
+
+    let plane = MandelbrotPlane::new(
+        re_min,
+        re_max,
+        im_min,
+        im_max,
+        params.width,
+        params.height,
+        params.max_iterations,
     )
+    .with_formula(params.formula.clone())
+    .with_interior_checks(params.interior_checks)
+    .with_colouring(params.colouring)
+    .with_interior_colouring(params.interior_colouring)
+    .with_bailout(params.bailout)
+    .with_palette_offset(params.palette_offset)
+    .with_projection(params.projection)
+    .with_kernel(if params.simd {
+        Kernel::detect()
+    } else {
+        Kernel::Scalar
+    });
+    if let Some(c) = params.julia {
+        plane.with_julia(c)
+    } else if perturbation {
+        // enough digits to tell apart neighbouring pixels, plus some spare. The pixels at the
+        // bottom of an exponential map are the smallest
+        let smallest_pixel = match params.projection {
+            Projection::Flat => resolution,
+            Projection::Exponential => {
+                Projection::exponential_offset(0.0, params.height as f64, params.width).norm()
+                    * real_width
+                    * std::f64::consts::TAU
+                    / params.width as f64
+            }
+        };
+        let scale = (-smallest_pixel.log10()).ceil().max(0.0) as u32 + 20;
+        let centre = params.centre.with_scale(scale);
+        let reference = ReferenceOrbit::new(&centre.re, &centre.im, params.max_iterations);
+        plane
+            .with_algorithm(Algorithm::Perturbation {
+                reference: Arc::new(reference),
+                series: None,
+            })
+            .with_series_approximation()
+    } else {
+        plane
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colours::DEFAULT_COLOURS;
+
+    fn view(
+        (re, im): (f64, f64),
+        resolution: f64,
+        max_iterations: u64,
+        interior_checks: InteriorChecks,
+    ) -> RenderParams {
+        RenderParams {
+            centre: ComplexDecimal::new(Decimal::from_f64(re, 0), Decimal::from_f64(im, 0)),
+            resolution: Decimal::from_f64(resolution, 0),
+            max_iterations,
+            width: 500,
+            height: 500,
+            interior_checks,
+            ..Default::default()
+        }
+    }
+
+    // Guessing against working out every pixel, on views with a lot of the inside of the set,
+    // with every colouring, and on minibrots surrounded by detail. Nothing's allowed to come out
+    // different. Views like the seahorse valley are left out, as their filaments are thin enough
+    // to slip between the pixels of a border and get filled in
+    #[test]
+    fn guessing_matches_brute_force() {
+        let mut views = vec![
+            view((-0.5, 0.0), 3.0 / 500.0, 1000, InteriorChecks::NONE),
+            view((-0.5, 0.0), 3.0 / 500.0, 1000, InteriorChecks::ALL),
+            // minibrots
+            view((-0.1011, 0.9563), 1e-5, 3000, InteriorChecks::NONE),
+            view((-1.25066, 0.02012), 5e-7, 5000, InteriorChecks::NONE),
+        ];
+        views.extend(Colouring::ALL.into_iter().map(|colouring| RenderParams {
+            colouring,
+            interior_colouring: InteriorColouring::Period,
+            ..view((-0.5, 0.0), 3.0 / 500.0, 500, InteriorChecks::ALL)
+        }));
+        for params in views {
+            let plane = plane_from_params(&params);
+            let brute_force = render_plane_parallel(&plane, &DEFAULT_COLOURS);
+            let guessed =
+                render_plane_guessing(&plane, &DEFAULT_COLOURS, &AtomicBool::new(false), |_| {})
+                    .unwrap();
+            let mismatched = brute_force
+                .pixels()
+                .chunks(3)
+                .zip(guessed.pixels().chunks(3))
+                .filter(|(a, b)| a != b)
+                .count();
+            assert_eq!(
+                mismatched, 0,
+                "{} of 250000 pixels differ at {} with {}",
+                mismatched, params.centre, params.colouring
+            );
+        }
+    }
+
+    // Rows get coloured in whatever order rayon gets to them, which mustn't change a single
+    // byte of the image, with every colouring and with perturbation too
+    #[test]
+    fn serial_and_parallel_are_identical() {
+        for colouring in Colouring::ALL {
+            for perturbation in [false, true] {
+                let params = RenderParams {
+                    width: 200,
+                    height: 150,
+                    colouring,
+                    perturbation,
+                    ..view((-0.745, 0.1), 1e-4, 1000, InteriorChecks::ALL)
+                };
+                let serial = render_from_params(&params, &DEFAULT_COLOURS);
+                let parallel = render_from_params_parallel(&params, &DEFAULT_COLOURS);
+                assert!(
+                    serial.pixels() == parallel.pixels(),
+                    "{} with perturbation {} came out differently",
+                    colouring,
+                    perturbation
+                );
+            }
+        }
+    }
 }
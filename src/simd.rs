@@ -0,0 +1,208 @@
+use crate::backend::{Escape, Mandelbrot, MandelbrotPoint};
+use num::Complex;
+
+// how many points the avx kernel iterates at once
+pub const LANES: usize = 4;
+
+// Which version of the z^2 + c loop to use for working out lots of points at once.
+// Both give exactly the same iteration counts and final z, the avx one is just faster
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Kernel {
+    // one point at a time, works everywhere
+    #[default]
+    Scalar,
+    // LANES points at a time in avx registers, falls back to Scalar if the cpu doesn't have avx
+    Avx,
+}
+
+impl Kernel {
+    // the fastest kernel this cpu can run
+    pub fn detect() -> Kernel {
+        if avx_available() {
+            Kernel::Avx
+        } else {
+            Kernel::Scalar
+        }
+    }
+
+    // Where the orbit of each (starting z, c) pair ends up under z^2 + c,
+    // with the same rules as MandelbrotPoint::julia_escape_with without periodicity checking
+    pub fn escapes(
+        self,
+        starts: &[(Complex<f64>, Complex<f64>)],
+        max_iterations: u64,
+        bailout: f64,
+    ) -> Vec<Escape> {
+        #[cfg(target_arch = "x86_64")]
+        if self == Kernel::Avx && avx_available() {
+            let mut escapes = Vec::with_capacity(starts.len());
+            for lanes in starts.chunks(LANES) {
+                // the spare lanes of the last chunk start outside the bailout, so they cost nothing
+                let mut z = [Complex::new(f64::INFINITY, 0.0); LANES];
+                let mut c = [Complex::new(0.0, 0.0); LANES];
+                for (lane, &(start, point)) in lanes.iter().enumerate() {
+                    z[lane] = start;
+                    c[lane] = point;
+                }
+                // safe as avx was checked for above
+                let lane_escapes = unsafe { escapes_avx(z, c, max_iterations, bailout) };
+                escapes.extend_from_slice(&lane_escapes[..lanes.len()]);
+            }
+            return escapes;
+        }
+        starts
+            .iter()
+            .map(|&(z, c)| {
+                MandelbrotPoint::new(z).julia_escape_with(
+                    &Mandelbrot,
+                    c,
+                    max_iterations,
+                    bailout,
+                    false,
+                )
+            })
+            .collect()
+    }
+}
+
+fn avx_available() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("avx")
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+}
+
+// The escape time loop for LANES points at once. All the lanes keep iterating until every one
+// of them has escaped or max_iterations is reached, but a lane that's escaped stops counting
+// iterations and keeps hold of the z it escaped with. The z that carries on is just thrown
+// away, which keeps the masking out of the way of the z^2 + c that every iteration waits on.
+// The arithmetic is done in the same order as Complex's z * z + c, without fused
+// multiply-adds, so every lane matches the scalar loop exactly
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn escapes_avx(
+    z: [Complex<f64>; LANES],
+    c: [Complex<f64>; LANES],
+    max_iterations: u64,
+    bailout: f64,
+) -> [Escape; LANES] {
+    use std::arch::x86_64::*;
+
+    let mut z_re = _mm256_setr_pd(z[0].re, z[1].re, z[2].re, z[3].re);
+    let mut z_im = _mm256_setr_pd(z[0].im, z[1].im, z[2].im, z[3].im);
+    let c_re = _mm256_setr_pd(c[0].re, c[1].re, c[2].re, c[3].re);
+    let c_im = _mm256_setr_pd(c[0].im, c[1].im, c[2].im, c[3].im);
+    let bailout_squared = _mm256_set1_pd(bailout * bailout);
+    let one = _mm256_set1_pd(1.0);
+    let mut escaped_re = z_re;
+    let mut escaped_im = z_im;
+    // iteration counts are kept as floats to stay in the same registers, which is exact up to 2^53
+    let mut iterations = _mm256_setzero_pd();
+    // every bit set for the lanes that haven't escaped yet
+    let mut running = _mm256_cmp_pd::<_CMP_EQ_OQ>(one, one);
+    // the lanes that are still running have all done the same number of iterations
+    let mut iteration = 0;
+    loop {
+        escaped_re = _mm256_blendv_pd(escaped_re, z_re, running);
+        escaped_im = _mm256_blendv_pd(escaped_im, z_im, running);
+        let re_squared = _mm256_mul_pd(z_re, z_re);
+        let im_squared = _mm256_mul_pd(z_im, z_im);
+        let norm_sqr = _mm256_add_pd(re_squared, im_squared);
+        running = _mm256_and_pd(
+            running,
+            _mm256_cmp_pd::<_CMP_LE_OQ>(norm_sqr, bailout_squared),
+        );
+        if _mm256_movemask_pd(running) == 0 || iteration == max_iterations {
+            break;
+        }
+        let re_im = _mm256_mul_pd(z_re, z_im);
+        z_re = _mm256_add_pd(_mm256_sub_pd(re_squared, im_squared), c_re);
+        z_im = _mm256_add_pd(_mm256_add_pd(re_im, re_im), c_im);
+        iterations = _mm256_add_pd(iterations, _mm256_and_pd(running, one));
+        iteration += 1;
+    }
+
+    let mut re = [0.0; LANES];
+    let mut im = [0.0; LANES];
+    let mut counts = [0.0; LANES];
+    _mm256_storeu_pd(re.as_mut_ptr(), escaped_re);
+    _mm256_storeu_pd(im.as_mut_ptr(), escaped_im);
+    _mm256_storeu_pd(counts.as_mut_ptr(), iterations);
+    std::array::from_fn(|lane| Escape {
+        iterations: counts[lane] as u64,
+        z: Complex::new(re[lane], im[lane]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the same bits, apart from NaNs which only need to both be NaN
+    fn same(a: f64, b: f64) -> bool {
+        a.to_bits() == b.to_bits() || a.is_nan() && b.is_nan()
+    }
+
+    #[test]
+    fn avx_matches_scalar() {
+        if !avx_available() {
+            eprintln!("skipped, this cpu doesn't have avx");
+            return;
+        }
+        let zero = Complex::new(0.0, 0.0);
+        let mut starts: Vec<(Complex<f64>, Complex<f64>)> = (0..=60)
+            .flat_map(|x| {
+                (0..=50).map(move |y| {
+                    (
+                        zero,
+                        Complex::new(-2.2 + x as f64 * 0.05, -1.25 + y as f64 * 0.05),
+                    )
+                })
+            })
+            .collect();
+        // julia sets start from the point rather than 0
+        let julia = Complex::new(-0.8, 0.156);
+        starts.extend((0..200).map(|i| (Complex::from_polar(1.2, i as f64 * 0.1), julia)));
+        for edge in [
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            1e300,
+            -1e300,
+            -0.0,
+            f64::MIN_POSITIVE,
+        ] {
+            starts.push((zero, Complex::new(edge, 0.0)));
+            starts.push((zero, Complex::new(-0.5, edge)));
+            starts.push((Complex::new(edge, edge), Complex::new(-0.1, 0.1)));
+        }
+        for (max_iterations, bailout) in [(300, 2.0), (300, 256.0), (0, 2.0), (1, 2.0)] {
+            // every length of slice up to a few chunks, so the last chunk has each number of lanes
+            for length in (0..=3 * LANES + 1).chain([starts.len()]) {
+                for chunk in starts.chunks(length.max(1)) {
+                    let chunk = &chunk[..length.min(chunk.len())];
+                    let scalar = Kernel::Scalar.escapes(chunk, max_iterations, bailout);
+                    let avx = Kernel::Avx.escapes(chunk, max_iterations, bailout);
+                    assert_eq!(scalar.len(), chunk.len());
+                    assert_eq!(avx.len(), chunk.len());
+                    for ((scalar, avx), start) in scalar.iter().zip(&avx).zip(chunk) {
+                        assert!(
+                            scalar.iterations == avx.iterations
+                                && same(scalar.z.re, avx.z.re)
+                                && same(scalar.z.im, avx.z.im),
+                            "{:?} with {} iterations: scalar {:?}, avx {:?}",
+                            start,
+                            max_iterations,
+                            scalar,
+                            avx
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use crate::backend::{Formula, MandelbrotPlane};
+use crate::renderer::PixelBuffer;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+// how different a pixel has to be from one of its neighbours, in any of red, green or blue,
+// for adaptive supersampling to count it as being on an edge
+const ADAPTIVE_THRESHOLD: u8 = 24;
+
+// Where the samples go inside each pixel
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum SamplePattern {
+    // evenly spaced rows and columns
+    #[default]
+    Grid,
+    // one sample somewhere random in each square of the grid, which swaps the regular
+    // patterns a grid leaves on fine detail for noise
+    Jittered,
+    // the grid turned by atan(1/2) so no two samples share a row or column,
+    // which does a lot better on nearly horizontal and vertical edges
+    RotatedGrid,
+}
+
+impl SamplePattern {
+    pub const ALL: [SamplePattern; 3] = [
+        SamplePattern::Grid,
+        SamplePattern::Jittered,
+        SamplePattern::RotatedGrid,
+    ];
+}
+
+impl std::fmt::Display for SamplePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SamplePattern::Grid => "Grid",
+                SamplePattern::Jittered => "Jittered",
+                SamplePattern::RotatedGrid => "Rotated grid",
+            }
+        )
+    }
+}
+
+// How many samples to take of each pixel and where. The colours of the samples get averaged
+// in linear light, as averaging the sRGB values makes edges come out too dark
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Supersampling {
+    // samples across and down each pixel, so 1 is one sample per pixel and no supersampling
+    pub size: u32,
+    pub pattern: SamplePattern,
+    // only supersample pixels that come out very different from a neighbour,
+    // which is usually only a small part of the image
+    pub adaptive: bool,
+}
+
+impl Default for Supersampling {
+    fn default() -> Self {
+        Supersampling {
+            size: 1,
+            pattern: SamplePattern::default(),
+            adaptive: false,
+        }
+    }
+}
+
+impl Supersampling {
+    pub fn enabled(&self) -> bool {
+        self.size > 1
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.size * self.size
+    }
+
+    // How far a render with one sample per pixel that's pixels_done pixels in has got through
+    // the whole render, counting that as one sample out of the ones to come for every pixel
+    pub fn base_progress(&self, pixels_done: u64) -> u64 {
+        if self.enabled() {
+            pixels_done / (self.samples() as u64 + 1)
+        } else {
+            pixels_done
+        }
+    }
+
+    // where the samples of pixel (x, y) go, in pixels from its own point,
+    // spread over the pixel sized square around it
+    fn offsets(&self, x: u32, y: u32) -> Vec<(f64, f64)> {
+        let size = self.size.max(1);
+        let spacing = 1.0 / size as f64;
+        let (sin, cos) = 0.5_f64.atan().sin_cos();
+        // back into the pixel after being turned, keeping the samples on the same lattice
+        let wrap = |offset: f64| (offset + 0.5).rem_euclid(1.0) - 0.5;
+        (0..size * size)
+            .map(|i| {
+                let (column, row) = ((i % size) as f64, (i / size) as f64);
+                match self.pattern {
+                    SamplePattern::Grid => {
+                        ((column + 0.5) * spacing - 0.5, (row + 0.5) * spacing - 0.5)
+                    }
+                    SamplePattern::Jittered => {
+                        let random = hash(x, y, i);
+                        // the top and bottom 32 bits are independent enough for this
+                        let along = (random >> 32) as f64 / (1u64 << 32) as f64;
+                        let down = (random & 0xffff_ffff) as f64 / (1u64 << 32) as f64;
+                        (
+                            (column + along) * spacing - 0.5,
+                            (row + down) * spacing - 0.5,
+                        )
+                    }
+                    SamplePattern::RotatedGrid => {
+                        let along = (column + 0.5) * spacing - 0.5;
+                        let down = (row + 0.5) * spacing - 0.5;
+                        (
+                            wrap(along * cos - down * sin),
+                            wrap(along * sin + down * cos),
+                        )
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+// Supersample an image that's already been rendered with one sample per pixel. Every pixel
+// gets worked out again from its samples, or with adaptive supersampling only the ones on edges.
+// Progress carries on from where base_progress left off, and is called with how far through
+// the whole render it is in pixels after each row. Gives up and returns None once cancelled is set
+pub fn supersample<F: Formula>(
+    set: &MandelbrotPlane<F>,
+    colours: &[(u8, u8, u8)],
+    supersampling: Supersampling,
+    mut image: PixelBuffer,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64) + Sync,
+) -> Option<PixelBuffer> {
+    if !supersampling.enabled() {
+        return Some(image);
+    }
+    let (width, height) = (image.width(), image.height());
+    let rows: Vec<Vec<u32>> = (0..height)
+        .into_par_iter()
+        .map(|y| {
+            (0..width)
+                .filter(|&x| !supersampling.adaptive || on_edge(&image, x, y))
+                .collect()
+        })
+        .collect();
+
+    let pixels = width as u64 * height as u64;
+    let base = supersampling.base_progress(pixels);
+    let to_resample = rows.iter().map(|xs| xs.len() as u64).sum::<u64>().max(1);
+    let resampled_so_far = AtomicU64::new(0);
+    let samples = supersampling.samples() as usize;
+    let resampled: Vec<Vec<(u8, u8, u8)>> = rows
+        .par_iter()
+        .enumerate()
+        .map(|(y, xs)| {
+            if cancelled.load(Ordering::Relaxed) {
+                return Vec::new();
+            }
+            let y = y as u32;
+            // the jitter goes by where the pixel is in the whole plane, so the samples don't
+            // move if it's rendered in parts
+            let row = set.first_row() as u32 + y;
+            let points: Vec<_> = xs
+                .iter()
+                .flat_map(|&x| {
+                    supersampling
+                        .offsets(x, row)
+                        .into_iter()
+                        .map(move |(dx, dy)| set.sample_at(x as f64 + dx, y as f64 + dy))
+                })
+                .collect();
+            let row = set
+                .colours(&points, colours)
+                .chunks_exact(samples)
+                .map(average)
+                .collect();
+            let done = xs.len() as u64;
+            let done = resampled_so_far.fetch_add(done, Ordering::Relaxed) + done;
+            progress(base + done * (pixels - base) / to_resample);
+            row
+        })
+        .collect();
+    if cancelled.load(Ordering::Relaxed) {
+        return None;
+    }
+    for (y, (xs, row)) in rows.iter().zip(resampled).enumerate() {
+        for (&x, colour) in xs.iter().zip(row) {
+            image.set(x, y as u32, colour);
+        }
+    }
+    Some(image)
+}
+
+// whether a pixel is very different from any of the pixels next to it
+fn on_edge(image: &PixelBuffer, x: u32, y: u32) -> bool {
+    let (r, g, b) = image.get(x, y);
+    let differs = |(nr, ng, nb): (u8, u8, u8)| {
+        r.abs_diff(nr) > ADAPTIVE_THRESHOLD
+            || g.abs_diff(ng) > ADAPTIVE_THRESHOLD
+            || b.abs_diff(nb) > ADAPTIVE_THRESHOLD
+    };
+    (x > 0 && differs(image.get(x - 1, y)))
+        || (y > 0 && differs(image.get(x, y - 1)))
+        || (x + 1 < image.width() && differs(image.get(x + 1, y)))
+        || (y + 1 < image.height() && differs(image.get(x, y + 1)))
+}
+
+// the average of some sRGB colours, worked out in linear light
+fn average(colours: &[(u8, u8, u8)]) -> (u8, u8, u8) {
+    let linear = linear_table();
+    let mut total = [0.0; 3];
+    for &(r, g, b) in colours {
+        total[0] += linear[r as usize];
+        total[1] += linear[g as usize];
+        total[2] += linear[b as usize];
+    }
+    let channel = |total: f64| to_srgb(total / colours.len() as f64);
+    (channel(total[0]), channel(total[1]), channel(total[2]))
+}
+
+// some sRGB colours mixed together in linear light, with weights that add up to 1
+pub fn blend(colours: impl IntoIterator<Item = ((u8, u8, u8), f64)>) -> (u8, u8, u8) {
+    let linear = linear_table();
+    let mut total = [0.0; 3];
+    for ((r, g, b), weight) in colours {
+        total[0] += linear[r as usize] * weight;
+        total[1] += linear[g as usize] * weight;
+        total[2] += linear[b as usize] * weight;
+    }
+    (to_srgb(total[0]), to_srgb(total[1]), to_srgb(total[2]))
+}
+
+// every sRGB channel value in linear light, from 0 to 1
+fn linear_table() -> &'static [f64; 256] {
+    static TABLE: OnceLock<[f64; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        std::array::from_fn(|channel| {
+            let channel = channel as f64 / 255.0;
+            if channel <= 0.04045 {
+                channel / 12.92
+            } else {
+                ((channel + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    })
+}
+
+fn to_srgb(linear: f64) -> u8 {
+    let channel = if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (channel * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+// A well mixed number from a pixel and which of its samples it's for (splitmix64), so jittered
+// samples land in the same places every time the same view is rendered
+fn hash(x: u32, y: u32, sample: u32) -> u64 {
+    let mut z = ((x as u64) << 32 | y as u64) ^ (sample as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colours::DEFAULT_COLOURS;
+    use crate::precision::{ComplexDecimal, Decimal};
+    use crate::renderer::{plane_from_params, render_plane, RenderParams};
+
+    // half black and half white is 188 in linear light, where averaging sRGB would give 128
+    #[test]
+    fn blends_in_linear_light() {
+        let (black, white) = ((0, 0, 0), (255, 255, 255));
+        assert_eq!(blend([(black, 0.5), (white, 0.5)]), (188, 188, 188));
+        assert_eq!(average(&[black, white]), (188, 188, 188));
+        assert_eq!(average(&[(10, 100, 200); 5]), (10, 100, 200));
+        assert_eq!(blend([((0, 255, 0), 1.0)]), (0, 255, 0));
+    }
+
+    // size squared samples in every pattern, all different and inside the pixel
+    #[test]
+    fn samples_stay_inside_the_pixel() {
+        for pattern in SamplePattern::ALL {
+            for size in 1..=6 {
+                let supersampling = Supersampling {
+                    size,
+                    pattern,
+                    adaptive: false,
+                };
+                for (x, y) in [(0, 0), (17, 3), (1000, 999)] {
+                    let offsets = supersampling.offsets(x, y);
+                    assert_eq!(offsets.len(), (size * size) as usize, "{}", pattern);
+                    for (i, &(dx, dy)) in offsets.iter().enumerate() {
+                        assert!(
+                            (-0.5..=0.5).contains(&dx) && (-0.5..=0.5).contains(&dy),
+                            "{} sample at ({}, {}) with size {}",
+                            pattern,
+                            dx,
+                            dy,
+                            size
+                        );
+                        assert!(!offsets[..i].contains(&(dx, dy)));
+                    }
+                }
+            }
+        }
+    }
+
+    // Adaptive supersampling leaves pixels that aren't on an edge exactly as they were,
+    // and still changes the ones that are
+    #[test]
+    fn adaptive_leaves_flat_areas_alone() {
+        let params: RenderParams = RenderParams {
+            centre: ComplexDecimal::new("-0.5".parse().unwrap(), Decimal::zero(0)),
+            resolution: Decimal::from_f64(3.0 / 120.0, 0),
+            max_iterations: 200,
+            width: 120,
+            height: 120,
+            ..Default::default()
+        };
+        let plane = plane_from_params(&params);
+        let base = render_plane(&plane, &DEFAULT_COLOURS);
+        let supersampling = Supersampling {
+            size: 3,
+            pattern: SamplePattern::Grid,
+            adaptive: true,
+        };
+        let supersampled = supersample(
+            &plane,
+            &DEFAULT_COLOURS,
+            supersampling,
+            base.clone(),
+            &AtomicBool::new(false),
+            |_| {},
+        )
+        .unwrap();
+        let mut changed = 0;
+        for y in 0..base.height() {
+            for x in 0..base.width() {
+                if on_edge(&base, x, y) {
+                    changed += (supersampled.get(x, y) != base.get(x, y)) as u32;
+                } else {
+                    assert_eq!(supersampled.get(x, y), base.get(x, y), "({}, {})", x, y);
+                }
+            }
+        }
+        assert!(changed > 100, "only {} edge pixels changed", changed);
+    }
+}
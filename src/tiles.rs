@@ -0,0 +1,353 @@
+use crate::backend::{
+    Algorithm, Colouring, Escape, Formula, MandelbrotPlane, MandelbrotPoint, Projection,
+};
+use crate::renderer::{PixelBuffer, RenderParams, PROGRESSIVE_STEP};
+use num::Complex;
+use rayon::prelude::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// width and height of a tile in pixels
+pub const TILE_SIZE: i64 = 64;
+// how many tiles are kept around, each one takes up about 100kB
+pub const TILE_CACHE_SIZE: usize = 512;
+// how finely the grid gets shifted to line up with a view, in fractions of a pixel. Finer than
+// this and moving the view by whole pixels would shift it differently from rounding errors
+const SUBPIXELS: f64 = 4294967296.0;
+
+// Everything the iteration data in a tile depends on. At each zoom level the tiles are laid
+// out on a grid that's fixed to the complex plane, with tile (0, 0) starting at 0 + 0i,
+// so panning or zooming back out to a level that's been seen before finds the same tiles again.
+// The grid gets shifted by however much less than a pixel it takes for the pixels to land
+// exactly where the view has them, and only views shifted the same way share tiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileKey<F: Formula> {
+    // the size of a pixel, which is what the zoom level comes down to
+    pub resolution: f64,
+    pub x: i64,
+    pub y: i64,
+    // how far the grid is shifted along each axis, in 1/SUBPIXELS of a pixel
+    pub shift: (u32, u32),
+    pub formula: F,
+    pub max_iterations: u64,
+    pub bailout: f64,
+    pub julia: Option<Complex<f64>>,
+}
+
+// The most recently used tiles, each one being where the orbit of every pixel in it ended up
+// in rows. Only the iteration data is kept, so changing the palette still reuses the tiles
+#[derive(Debug, Default)]
+pub struct TileCache<F: Formula> {
+    tiles: VecDeque<(TileKey<F>, Arc<[Escape]>)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<F: Formula + PartialEq> TileCache<F> {
+    // a tile that's already been rendered, which then counts as the most recently used
+    pub fn get(&mut self, key: &TileKey<F>) -> Option<Arc<[Escape]>> {
+        let Some(index) = self.tiles.iter().position(|(k, _)| k == key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        let tile = self.tiles.remove(index)?;
+        let escapes = tile.1.clone();
+        self.tiles.push_back(tile);
+        Some(escapes)
+    }
+
+    // keep a newly rendered tile, forgetting the least recently used one if the cache is full
+    pub fn insert(&mut self, key: TileKey<F>, escapes: Arc<[Escape]>) {
+        self.tiles.push_back((key, escapes));
+        if self.tiles.len() > TILE_CACHE_SIZE {
+            self.tiles.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    // how many tiles have been found or not found in the cache over all renders
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+// Where a tile ended up in a rendered view, which can start above or left of the image,
+// and whether it came out of the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderedTile {
+    pub x: i64,
+    pub y: i64,
+    pub cached: bool,
+}
+
+// Whether a view can be rendered out of tiles. The tiles only hold escape times, and the
+// points in them are worked out straight from their place on the grid, which needs the plane
+// to be in plain f64s rather than offsets from a reference orbit. Supersampling would need
+// the view to be where the plane puts it rather than snapped to the grid
+pub fn can_tile<F: Formula>(params: &RenderParams<F>, plane: &MandelbrotPlane<F>) -> bool {
+    params.colouring == Colouring::EscapeTime
+        && !params.guessing
+        && !params.supersampling.enabled()
+        && params.projection == Projection::Flat
+        && matches!(plane.algorithm(), Algorithm::Direct)
+}
+
+// Render a view out of tiles, taking whatever it can from the cache and rendering the rest,
+// which then goes into the cache too. The pixels come out where render_plane would put them,
+// give or take rounding errors from shifting the grid. on_pass gets shown
+// a rough version of the whole view first, then the view with the cached tiles in, then the
+// view after each batch of new tiles is done. Gives up and returns None once cancelled is set
+pub fn render_tiles<F: Formula + Clone + PartialEq>(
+    params: &RenderParams<F>,
+    plane: &MandelbrotPlane<F>,
+    colours: &[(u8, u8, u8)],
+    cache: &Mutex<TileCache<F>>,
+    cancelled: &AtomicBool,
+    progress: impl Fn(u64),
+    mut on_pass: impl FnMut(&PixelBuffer),
+) -> Option<(PixelBuffer, Vec<RenderedTile>)> {
+    let resolution = params.resolution.to_f64();
+    let centre = params.centre.to_f64();
+    let (width, height) = (params.width as i64, params.height as i64);
+    // the pixel of the tile grid in the top left corner of the view, and how far the grid has
+    // to be shifted for it to be exactly where the plane has its top left pixel
+    let (left, shift_x) = grid_position(centre.re / resolution - width as f64 / 2.0);
+    let (top, shift_y) = grid_position(centre.im / resolution - height as f64 / 2.0);
+    let point = |x: i64, y: i64| {
+        MandelbrotPoint::new(Complex::new(
+            (x as f64 + shift_x as f64 / SUBPIXELS) * resolution,
+            (y as f64 + shift_y as f64 / SUBPIXELS) * resolution,
+        ))
+    };
+
+    let mut buffer = PixelBuffer::new(width as u32, height as u32);
+    if width == 0 || height == 0 {
+        return Some((buffer, Vec::new()));
+    }
+    let keys: Vec<TileKey<F>> = (top.div_euclid(TILE_SIZE)
+        ..=(top + height - 1).div_euclid(TILE_SIZE))
+        .flat_map(|y| {
+            (left.div_euclid(TILE_SIZE)..=(left + width - 1).div_euclid(TILE_SIZE))
+                .map(move |x| (x, y))
+        })
+        .map(|(x, y)| TileKey {
+            resolution,
+            x,
+            y,
+            shift: (shift_x, shift_y),
+            formula: params.formula.clone(),
+            max_iterations: params.max_iterations,
+            bailout: params.bailout,
+            julia: params.julia,
+        })
+        .collect();
+    let found: Vec<Option<Arc<[Escape]>>> = {
+        let mut cache = cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        keys.iter().map(|key| cache.get(key)).collect()
+    };
+    let tiles = keys
+        .iter()
+        .zip(&found)
+        .map(|(key, escapes)| RenderedTile {
+            x: key.x * TILE_SIZE - left,
+            y: key.y * TILE_SIZE - top,
+            cached: escapes.is_some(),
+        })
+        .collect();
+
+    // the colours of the pixels of a tile that are in the view, and where they go
+    let colour_tile = |key: &TileKey<F>, escapes: &[Escape]| -> Vec<(u32, u32, (u8, u8, u8))> {
+        escapes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &escape)| {
+                let x = key.x * TILE_SIZE + i as i64 % TILE_SIZE;
+                let y = key.y * TILE_SIZE + i as i64 / TILE_SIZE;
+                let inside = (left..left + width).contains(&x) && (top..top + height).contains(&y);
+                inside.then(|| {
+                    let colour = plane.escape_time_colour(point(x, y), escape, colours);
+                    ((x - left) as u32, (y - top) as u32, colour)
+                })
+            })
+            .collect()
+    };
+    let draw = |buffer: &mut PixelBuffer, pixels: Vec<(u32, u32, (u8, u8, u8))>| {
+        for (x, y, colour) in pixels {
+            buffer.set(x, y, colour);
+        }
+    };
+
+    let missing: Vec<&TileKey<F>> = keys
+        .iter()
+        .zip(&found)
+        .filter(|(_, escapes)| escapes.is_none())
+        .map(|(key, _)| key)
+        .collect();
+    if !missing.is_empty() {
+        // a quick look at the whole view, at the same resolution as the first progressive pass
+        let step = PROGRESSIVE_STEP as i64;
+        let rows: Vec<i64> = (0..height).step_by(step as usize).collect();
+        let rows: Vec<_> = rows
+            .into_par_iter()
+            .map(|y| {
+                let points: Vec<_> = (0..width)
+                    .step_by(step as usize)
+                    .map(|x| point(left + x, top + y))
+                    .collect();
+                (y, plane.colours(&points, colours))
+            })
+            .collect();
+        for (y, row) in rows {
+            for (block, colour) in row.into_iter().enumerate() {
+                let x = block as i64 * step;
+                for y in y..(y + step).min(height) {
+                    for x in x..(x + step).min(width) {
+                        buffer.set(x as u32, y as u32, colour);
+                    }
+                }
+            }
+        }
+        on_pass(&buffer);
+    }
+    let cached: Vec<_> = keys
+        .par_iter()
+        .zip(&found)
+        .filter_map(|(key, escapes)| Some(colour_tile(key, escapes.as_ref()?)))
+        .collect();
+    for pixels in cached {
+        draw(&mut buffer, pixels);
+    }
+    if missing.len() < keys.len() && !missing.is_empty() {
+        on_pass(&buffer);
+    }
+
+    // enough tiles at once to keep every core busy, but not so few that the view gets redrawn
+    // more than about 8 times
+    let batch_size = (rayon::current_num_threads() * 2).max(missing.len().div_ceil(8));
+    for (batch, keys) in missing.chunks(batch_size).enumerate() {
+        let rendered: Vec<(Arc<[Escape]>, _)> = keys
+            .par_iter()
+            .map(|key| {
+                let points: Vec<_> = (0..TILE_SIZE * TILE_SIZE)
+                    .map(|i| {
+                        point(
+                            key.x * TILE_SIZE + i % TILE_SIZE,
+                            key.y * TILE_SIZE + i / TILE_SIZE,
+                        )
+                    })
+                    .collect();
+                let escapes = plane.escapes(&points);
+                let pixels = colour_tile(key, &escapes);
+                (escapes.into(), pixels)
+            })
+            .collect();
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut cache = cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (&key, (escapes, pixels)) in keys.iter().zip(rendered) {
+            draw(&mut buffer, pixels);
+            cache.insert(key.clone(), escapes);
+        }
+        drop(cache);
+        let done = batch * batch_size + keys.len();
+        progress((done * (width * height) as usize / missing.len()) as u64);
+        if done < missing.len() {
+            on_pass(&buffer);
+        }
+    }
+    Some((buffer, tiles))
+}
+
+// The whole pixel of the grid a position in pixels from 0 is in, and how far past it the position
+// is in 1/SUBPIXELS of a pixel, which stays the same when the view moves by whole pixels
+fn grid_position(pixels: f64) -> (i64, u32) {
+    let whole = pixels.floor();
+    let shift = ((pixels - whole) * SUBPIXELS).round();
+    if shift >= SUBPIXELS {
+        (whole as i64 + 1, 0)
+    } else {
+        (whole as i64, shift as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Mandelbrot;
+    use crate::colours::DEFAULT_COLOURS;
+    use crate::precision::{ComplexDecimal, Decimal};
+    use crate::renderer::{plane_from_params, render_plane_parallel};
+
+    // A view that's a fraction of a pixel off the tile grid along both axes. It's outside the
+    // set, as pixels near the edge are chaotic enough that the last bit of their position
+    // can change their colour
+    fn view(re: f64, im: f64) -> RenderParams {
+        let resolution = 1e-3;
+        RenderParams {
+            centre: ComplexDecimal::new(
+                Decimal::from_f64(re + 0.37 * resolution, 0),
+                Decimal::from_f64(im + 0.81 * resolution, 0),
+            ),
+            resolution: Decimal::from_f64(resolution, 0),
+            max_iterations: 1000,
+            width: 300,
+            height: 200,
+            ..Default::default()
+        }
+    }
+
+    // how many pixels of a view come out differently out of tiles than straight from the plane
+    fn mismatched(params: &RenderParams, cache: &Mutex<TileCache<Mandelbrot>>) -> usize {
+        let plane = plane_from_params(params);
+        assert!(can_tile(params, &plane));
+        let direct = render_plane_parallel(&plane, &DEFAULT_COLOURS);
+        let (tiled, _) = render_tiles(
+            params,
+            &plane,
+            &DEFAULT_COLOURS,
+            cache,
+            &AtomicBool::new(false),
+            |_| {},
+            |_| {},
+        )
+        .unwrap();
+        direct
+            .pixels()
+            .chunks(3)
+            .zip(tiled.pixels().chunks(3))
+            .filter(|(a, b)| a != b)
+            .count()
+    }
+
+    // Tiles put the pixels where the plane would even off the grid, where snapping to it used
+    // to move the view, and moving by whole pixels still finds the tiles from before
+    #[test]
+    fn tiles_line_up_with_the_view() {
+        let cache = Mutex::new(TileCache::default());
+        let params = view(0.6, 0.6);
+        assert_eq!(mismatched(&params, &cache), 0);
+        let moved = RenderParams {
+            centre: ComplexDecimal::new(
+                &params.centre.re - &Decimal::from_f64(20.0 * 1e-3, 0),
+                params.centre.im.clone(),
+            ),
+            ..params
+        };
+        assert_eq!(mismatched(&moved, &cache), 0);
+        assert!(cache.lock().unwrap().hits() > 0);
+    }
+}